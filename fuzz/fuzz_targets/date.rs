@@ -0,0 +1,12 @@
+//! Parses a date, which must not panic, and checks that writing it back gives the same date
+#![no_main]
+
+use gitlab_todotxt_sync::todo::Date;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|text: &str| {
+    if let Ok(date) = text.parse::<Date>() {
+        assert_eq!(date.to_string().parse::<Date>().unwrap(), date);
+        assert_eq!(Date::from_days(date.days()), date);
+    }
+});
@@ -0,0 +1,11 @@
+//! Parses a meta tag, which must not panic, and checks that writing it back gives the same tag
+#![no_main]
+
+use gitlab_todotxt_sync::todo::DescriptionPart;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|tag: &str| {
+    if let Ok(part) = DescriptionPart::parse(tag) {
+        assert_eq!(part.to_string(), tag);
+    }
+});
@@ -0,0 +1,13 @@
+//! Escapes a text from Gitlab, which must not panic, and checks that the result has no meta tags
+//! and unescapes to the original text
+#![no_main]
+
+use gitlab_todotxt_sync::todo::Todo;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|text: &str| {
+    let escaped = Todo::escape_description(text);
+    assert_eq!(Todo::unescape_description(&escaped), text);
+    let todo = Todo::new(false, None, None, None, escaped.into_owned()).unwrap();
+    assert_eq!(todo.find_meta().count(), 0, "{todo}");
+});
@@ -0,0 +1,16 @@
+//! Parses a line of a todo file, which must not panic, and checks that writing it back gives
+//! the same todo
+#![no_main]
+
+use gitlab_todotxt_sync::todo::Todo;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|line: &str| {
+    let Ok(todo) = line.parse::<Todo>() else {
+        return;
+    };
+    let _ = todo.validate();
+    let _ = todo.find_meta().count();
+    let reparsed: Todo = todo.to_string().parse().expect("A written todo can be parsed");
+    assert_eq!(reparsed, todo);
+});
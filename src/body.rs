@@ -0,0 +1,169 @@
+use crate::gitlab::GitlabTodo;
+use documented::DocumentedFields;
+use regex::Regex;
+use serde::Deserialize;
+use std::sync::LazyLock;
+
+/// Target types whose title can replace the body of their todos
+const TITLED_TARGETS: [&str; 2] = ["MergeRequest", "Issue"];
+const ELLIPSIS: char = '…';
+
+/// How the text of a Gitlab todo is turned into a single line, see [`AppConfig::body`]
+///
+/// [`AppConfig::body`]: crate::config::AppConfig::body
+#[derive(Deserialize, Clone, Debug, PartialEq, DocumentedFields)]
+#[serde(deny_unknown_fields)]
+pub struct BodyConfig {
+    /// Use the title of the merge request or issue of a todo rather than its body, which is the
+    /// comment for mentions (default = true)
+    #[serde(default = "BodyConfig::default_use_title")]
+    pub use_title: bool,
+    /// Keep the Markdown syntax of the text, such as emphasis, links and code spans
+    #[serde(default)]
+    pub keep_markdown: bool,
+    /// Truncate the text to this number of characters, ending it with an ellipsis. Can be null
+    /// for no limit (default = 120)
+    #[serde(default = "BodyConfig::default_max_length")]
+    pub max_length: Option<usize>,
+}
+
+impl BodyConfig {
+    fn default_use_title() -> bool {
+        true
+    }
+
+    fn default_max_length() -> Option<usize> {
+        Some(120)
+    }
+}
+
+impl Default for BodyConfig {
+    fn default() -> Self {
+        Self {
+            use_title: Self::default_use_title(),
+            keep_markdown: false,
+            max_length: Self::default_max_length(),
+        }
+    }
+}
+
+/// The text of a todo, as written to the todo file: its title or body, normalized
+pub fn render(todo: &GitlabTodo, config: &BodyConfig) -> String {
+    let title = todo
+        .target_title
+        .as_deref()
+        .filter(|_| config.use_title && TITLED_TARGETS.contains(&todo.target_type.as_str()));
+    normalize(title.unwrap_or(&todo.body), config)
+}
+
+/// Turns a Markdown text into a single line: quoted replies and quick actions (`/assign`...)
+/// are dropped, the Markdown syntax is stripped unless kept by the config, whitespace is
+/// collapsed, and the result is truncated
+pub fn normalize(text: &str, config: &BodyConfig) -> String {
+    static QUICK_ACTION_REG: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(
+            r"^/(approve|assign|assign_reviewer|award|cc|close|copy_metadata|done|draft|due|estimate|label|lock|merge|milestone|move|ready|reassign|rebase|relabel|remove_due_date|reopen|request_review|shrug|spend|subscribe|tableflip|target_branch|title|todo|unapprove|unassign|unassign_reviewer|unlabel|unlock|unsubscribe|weight)\b",
+        )
+        .unwrap()
+    });
+    let mut parts = Vec::new();
+    let mut in_code = false;
+    for line in text.lines().map(str::trim) {
+        if line.starts_with("```") || line.starts_with("~~~") {
+            in_code = !in_code;
+            if !config.keep_markdown {
+                continue;
+            }
+        } else if !in_code && (line.starts_with('>') || QUICK_ACTION_REG.is_match(line)) {
+            continue;
+        }
+        if config.keep_markdown || in_code {
+            parts.push(line.to_string());
+        } else {
+            parts.push(strip_markdown(line));
+        }
+    }
+    let text = parts.join(" ");
+    let text: Vec<&str> = text.split_whitespace().collect();
+    truncate(&text.join(" "), config.max_length)
+}
+
+/// Removes the Markdown syntax of a line, keeping the text
+fn strip_markdown(line: &str) -> String {
+    static REPLACEMENTS: LazyLock<Vec<(Regex, &str)>> = LazyLock::new(|| {
+        [
+            // Headings, list items and task list boxes
+            (r"^(#{1,6}\s+|([-*+]|\d+[.)])\s+(\[[ xX]\]\s+)?)", ""),
+            // Images and links
+            (r"!?\[([^\]]*)\]\([^)]*\)", "$1"),
+            (r"`+([^`]+)`+", "$1"),
+            (r"\*\*([^*]+)\*\*|__([^_]+)__", "$1$2"),
+            (r"~~([^~]+)~~", "$1"),
+            (r"(^|[^\w*])\*([^*\s][^*]*)\*", "$1$2"),
+            (r"(^|[^\w_])_([^_\s][^_]*)_($|\W)", "$1$2$3"),
+            (r"</?[a-zA-Z][^>]*>", " "),
+        ]
+        .into_iter()
+        .map(|(reg, rep)| (Regex::new(reg).unwrap(), rep))
+        .collect()
+    });
+    let mut line = line.to_string();
+    for (reg, rep) in REPLACEMENTS.iter() {
+        line = reg.replace_all(&line, *rep).into_owned();
+    }
+    line
+}
+
+/// Truncates a text to `max` characters, the last one being an ellipsis if it was too long
+fn truncate(text: &str, max: Option<usize>) -> String {
+    match max {
+        Some(0) => String::new(),
+        Some(max) if text.chars().count() > max => {
+            let kept: String = text.chars().take(max.saturating_sub(1)).collect();
+            let mut kept = kept.trim_end().to_string();
+            kept.push(ELLIPSIS);
+            kept
+        }
+        _ => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let config = BodyConfig::default();
+        let normalize = |text: &str| normalize(text, &config);
+        assert_eq!(normalize("Please review"), "Please review");
+        assert_eq!(
+            normalize(
+                "> Can you check?\n> Thanks\n\n@jdoe **Done**, see [the docs](https://x.y/z) \
+                and `cargo test`.\n/assign @me\n\n- ~~old~~ _new_ *thing*\n- snake_case_name"
+            ),
+            "@jdoe Done, see the docs and cargo test. old new thing snake_case_name"
+        );
+        assert_eq!(
+            normalize("# Title\n```rust\nlet x = 2 * 3;\n```\n1. [x] <b>ok</b><br>/path/to/file"),
+            "Title let x = 2 * 3; ok /path/to/file"
+        );
+        assert_eq!(
+            normalize(&"word ".repeat(50)).chars().count(),
+            config.max_length.unwrap()
+        );
+        assert_eq!(
+            super::normalize(
+                "A **long** text",
+                &BodyConfig {
+                    keep_markdown: true,
+                    max_length: Some(8),
+                    ..config.clone()
+                }
+            ),
+            "A **lon…"
+        );
+        assert!(!normalize("a\r\nb\n\nc").contains(['\n', '\r']));
+        assert_eq!(truncate("Text", Some(0)), "");
+    }
+}
@@ -1,16 +1,23 @@
-use crate::gitlab::GitlabAPI;
-use crate::{AppResult, Error};
+use crate::body::BodyConfig;
+use crate::doctor::Repair;
+use crate::error::{AppResult, ConfigError};
+use crate::filter::{Filters, TodoFilter};
+use crate::gitlab::{ApiOptions, GitlabAPI, GitlabTodo};
+use crate::hooks::Hooks;
+use crate::notifier::NotifyConfig;
+use crate::webhook::WebhookConfig;
 use documented::DocumentedFields;
 use serde::Deserialize;
 use serde_json::from_str;
 use std::fmt::{Debug, Display, Formatter};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
-use url::{ParseError, Url};
+use url::Url;
 
+/// Configuration of the sync, read from a JSON file
 #[derive(Deserialize, Clone, Debug, DocumentedFields)]
-#[allow(dead_code)]
 pub struct AppConfig {
     /// Gitlab Personal Access Token for the target user
     pub gitlab_token: SecretString,
@@ -26,15 +33,102 @@ pub struct AppConfig {
     /// Disable escaping meta tags in Gitlab-originatig text (i.e. key:value will be synced as key\:value)
     #[serde(default)]
     pub no_escape_meta: bool,
-    /// Set this to your Gitlab username so item's authors can be specified if they're not you
-    /// (TODO: feature not implemented yet)
+    /// How the text of the todos is turned into a single line: whether to use the title of their
+    /// merge request or issue, keep Markdown, and truncate it, see [`BodyConfig`]
+    #[serde(default)]
+    pub body: BodyConfig,
+    /// Your Gitlab username. If set, the sync is aborted when the token belongs to another user
     #[serde(default)]
     pub username: Option<String>,
     /// Specifies what to do with items marked as done, see [`DonePolicy`] variants
     #[serde(default)]
     pub done_todo_policy: DonePolicy,
+    /// Add the hidden tag (h:1) to synced items whose threshold date (t:) is in the future, and
+    /// remove it once that date is reached
+    #[serde(default)]
+    pub hide_future_threshold: bool,
+    /// Mark todos as done on Gitlab when they are completed in the todo file. Requires a token
+    /// with the api scope
+    #[serde(default)]
+    pub push_completed: bool,
+    /// Which todos to sync, as lists of filters to `include` and `exclude`, see [`Filters`].
+    /// Filters match todos by `project`, `group`, `target_type`, `action` and `author`, with
+    /// globs such as "my-group/*". A single include filter is applied by Gitlab when possible
+    #[serde(default)]
+    pub filters: Filters,
+    /// Rules sending the todos they `match` (a filter, see [`TodoFilter`]) to another
+    /// `todo_file`, and/or adding `contexts` and `projects` to them, see [`Route`]. The first
+    /// matching rule applies
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// Timeout in seconds for connecting to the Gitlab instance
+    #[serde(default = "AppConfig::default_connect_timeout")]
+    pub connect_timeout: u64,
+    /// Timeout in seconds for receiving data from the Gitlab instance
+    #[serde(default = "AppConfig::default_read_timeout")]
+    pub read_timeout: u64,
+    /// Number of times requests to Gitlab are retried after a transient failure (connection
+    /// error, timeout, rate limiting or server error)
+    #[serde(default = "AppConfig::default_max_retries")]
+    pub max_retries: u32,
+    /// Maximum number of requests sent to the Gitlab instance at the same time
+    #[serde(default = "AppConfig::default_max_concurrent_requests")]
+    pub max_concurrent_requests: usize,
+    /// Abort the sync instead of deleting more than this number of synced items from the file.
+    /// Can be null for no limit
+    #[serde(default)]
+    pub max_deletions: Option<usize>,
+    /// Abort the sync instead of deleting more than this percentage of the synced items from
+    /// the file, if there are at least 10 of them. Can be null for no limit (default = 50)
+    #[serde(default = "AppConfig::default_max_deletion_percent")]
+    pub max_deletion_percent: Option<f64>,
+    /// Move the snoozed todos, those with a threshold date (`t:` tag) in the future, to this file
+    /// until the date passes. Can be null to keep them in their file (default)
+    #[serde(default)]
+    pub snoozed_file: Option<PathBuf>,
+    /// Repair the problems found in the todo files when syncing, such as duplicated ids or
+    /// synced lines without an id, as the doctor command does. Can be null to only log them
+    /// (default), see [`Repair`]
+    #[serde(default)]
+    pub repair: Option<Repair>,
+    /// Commands to run before fetching the todos, after planning the changes (which they can
+    /// veto or modify), and after writing the files, see [`Hooks`]
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// Notify new todos with a desktop notification or a command, see [`NotifyConfig`]. Can be
+    /// null for none
+    #[serde(default)]
+    pub notifications: Option<NotifyConfig>,
+    /// Receive Gitlab webhooks in daemon mode, to refresh the todos of a project as soon as one
+    /// of its merge requests or issues changes, see [`WebhookConfig`]. Can be null for none
+    #[serde(default)]
+    pub webhook: Option<WebhookConfig>,
+    /// Path to the file storing data between runs, such as the Gitlab user the todo file was
+    /// synced for. Each todo file has its own by default, named after it and a hash of its path
+    /// (default = $XDG_STATE_HOME/gitlab-todotxt-sync/todo-<hash>.json)
+    #[serde(default)]
+    pub state_file: PathBuf,
 }
 
+/// Where the todos matching a filter are synced, see [`AppConfig::routes`]
+#[derive(Deserialize, Clone, Debug, DocumentedFields)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Todos this route applies to
+    #[serde(rename = "match")]
+    pub filter: TodoFilter,
+    /// File to sync the todos to instead of [`AppConfig::todo_file`]
+    #[serde(default)]
+    pub todo_file: Option<PathBuf>,
+    /// Contexts to add to the todos, without the @
+    #[serde(default)]
+    pub contexts: Vec<String>,
+    /// Projects to add to the todos, without the +
+    #[serde(default)]
+    pub projects: Vec<String>,
+}
+
+/// What to do with todos that are done on Gitlab
 #[derive(Deserialize, Clone, Debug, Default, PartialEq, DocumentedFields)]
 #[serde(rename_all = "lowercase")]
 pub enum DonePolicy {
@@ -53,34 +147,123 @@ impl AppConfig {
         let mut text = String::new();
         File::open(path)
             .await
-            .expect(
-                format!(
-                    "Couldn't open config file {} for reading",
-                    path.to_str().unwrap()
-                )
-                .as_str(),
-            )
+            .map_err(|source| ConfigError::Read {
+                path: path.to_owned(),
+                source,
+            })?
             .read_to_string(&mut text)
             .await
-            .map_err(|e| Error::new(e).context("Couldn't read config file"))?;
-        let mut config: AppConfig = from_str(text.as_str()).map_err(|e| Error::new(e))?;
+            .map_err(|source| ConfigError::Read {
+                path: path.to_owned(),
+                source,
+            })?;
+        let mut config: AppConfig =
+            from_str(text.as_str()).map_err(|source| ConfigError::Invalid {
+                path: path.to_owned(),
+                source,
+            })?;
 
-        if let Ok(rel) = config.todo_file.strip_prefix("~") {
-            let home = dirs::home_dir().ok_or(Error::msg("Couldn't determine home directory"))?;
-            config.todo_file = home.join(rel);
+        let route_files = config
+            .routes
+            .iter_mut()
+            .filter_map(|r| r.todo_file.as_mut())
+            .chain(config.snoozed_file.as_mut());
+        for path in [&mut config.todo_file, &mut config.state_file]
+            .into_iter()
+            .chain(route_files)
+        {
+            if let Ok(rel) = path.strip_prefix("~") {
+                let home = dirs::home_dir().ok_or_else(|| {
+                    ConfigError::Other("Couldn't determine home directory".into())
+                })?;
+                *path = home.join(rel);
+            }
+        }
+        if config.state_file.as_os_str().is_empty() {
+            config.state_file = Self::default_state_file(&config.todo_file);
         }
 
         Ok(config)
     }
 
-    pub fn get_api(&self) -> Result<GitlabAPI, ParseError> {
-        GitlabAPI::new(self.gitlab_host.clone(), self.gitlab_token.clone())
+    /// The first route matching a todo
+    pub fn route(&self, todo: &GitlabTodo) -> Option<&Route> {
+        self.routes.iter().find(|r| r.filter.matches(todo))
+    }
+
+    /// The file a todo is synced to, according to the routes
+    pub fn todo_file_for(&self, todo: &GitlabTodo) -> &Path {
+        self.route(todo)
+            .and_then(|r| r.todo_file.as_deref())
+            .unwrap_or(&self.todo_file)
+    }
+
+    /// Every file the todos can be synced to, starting with [`Self::todo_file`]
+    pub fn todo_files(&self) -> Vec<&Path> {
+        let mut files = vec![self.todo_file.as_path()];
+        let routes = self.routes.iter().filter_map(|r| r.todo_file.as_deref());
+        for file in routes.chain(self.snoozed_file.as_deref()) {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+        files
+    }
+
+    pub fn get_api(&self) -> AppResult<GitlabAPI> {
+        let options = ApiOptions {
+            connect_timeout: Duration::from_secs(self.connect_timeout),
+            read_timeout: Duration::from_secs(self.read_timeout),
+            max_retries: self.max_retries,
+            max_concurrent_requests: self.max_concurrent_requests,
+            ..Default::default()
+        };
+        GitlabAPI::with_options(self.gitlab_host.clone(), self.gitlab_token.clone(), options)
     }
 
     fn default_context_tag() -> Option<String> {
         Some("gitlab".into())
     }
 
+    fn default_connect_timeout() -> u64 {
+        ApiOptions::default().connect_timeout.as_secs()
+    }
+
+    fn default_read_timeout() -> u64 {
+        ApiOptions::default().read_timeout.as_secs()
+    }
+
+    fn default_max_retries() -> u32 {
+        ApiOptions::default().max_retries
+    }
+
+    fn default_max_concurrent_requests() -> usize {
+        ApiOptions::default().max_concurrent_requests
+    }
+
+    fn default_max_deletion_percent() -> Option<f64> {
+        Some(50.0)
+    }
+
+    /// A state file per todo file, so that configs syncing different files (possibly for
+    /// different users) don't share their state
+    fn default_state_file(todo_file: &Path) -> PathBuf {
+        // FNV-1a, which unlike the std hasher is stable across Rust versions
+        let hash = todo_file
+            .as_os_str()
+            .as_encoded_bytes()
+            .iter()
+            .fold(0xcbf29ce484222325u64, |hash, &b| {
+                (hash ^ b as u64).wrapping_mul(0x100000001b3)
+            });
+        let stem = todo_file.file_stem().unwrap_or("todo".as_ref());
+        dirs::state_dir()
+            .or_else(dirs::data_local_dir)
+            .expect("Could not determine state dir")
+            .join("gitlab-todotxt-sync")
+            .join(format!("{}-{hash:016x}.json", stem.to_string_lossy()))
+    }
+
     fn default_todo_file() -> PathBuf {
         dirs::home_dir()
             .expect("Could not determine home dir")
@@ -96,12 +279,30 @@ impl Default for AppConfig {
             todo_file: Default::default(),
             context_tag: None,
             no_escape_meta: false,
+            body: Default::default(),
             username: None,
             done_todo_policy: Default::default(),
+            hide_future_threshold: false,
+            push_completed: false,
+            filters: Default::default(),
+            routes: Vec::new(),
+            connect_timeout: Self::default_connect_timeout(),
+            read_timeout: Self::default_read_timeout(),
+            max_retries: Self::default_max_retries(),
+            max_concurrent_requests: Self::default_max_concurrent_requests(),
+            max_deletions: None,
+            max_deletion_percent: Self::default_max_deletion_percent(),
+            snoozed_file: None,
+            repair: None,
+            hooks: Default::default(),
+            notifications: None,
+            webhook: None,
+            state_file: Default::default(),
         }
     }
 }
 
+/// A string that is redacted when displayed or debugged
 #[derive(Clone, Deserialize)]
 pub struct SecretString(pub String);
 
@@ -0,0 +1,252 @@
+use crate::error::{AppResult, IoError};
+use crate::sync::{Scope, SyncEngine, SyncOutcome};
+use crate::webhook::WebhookServer;
+use log::*;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::time::Instant;
+
+/// Upper bound for the delay between two syncs after failures, unless the interval is longer
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Runs a [`SyncEngine`] periodically, and whenever one of its todo files is changed by someone
+/// else, until shut down. If [`AppConfig::webhook`] is set, the todos affected by the received
+/// webhooks are also refreshed.
+///
+/// [`AppConfig::webhook`]: crate::config::AppConfig::webhook
+pub struct Daemon {
+    engine: SyncEngine,
+    interval: Duration,
+    debounce: Duration,
+}
+
+impl Daemon {
+    pub fn new(engine: SyncEngine, interval: Duration) -> Self {
+        Self {
+            engine,
+            interval,
+            debounce: Duration::from_secs(2),
+        }
+    }
+
+    /// Time to wait after a change to a todo file, so that a burst of writes triggers a single
+    /// sync
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Syncs until `shutdown` completes. A sync in progress is completed first, so that files
+    /// aren't left half-written.
+    ///
+    /// Failed syncs are logged and retried after a delay doubling with each consecutive failure,
+    /// from the interval up to an hour (or the interval if longer).
+    pub async fn run(self, shutdown: impl Future<Output = ()>) -> AppResult<()> {
+        let files: Vec<PathBuf> = self
+            .engine
+            .config()
+            .todo_files()
+            .into_iter()
+            .map(absolute)
+            .collect::<Result<_, _>>()?;
+        let (tx, mut changes) = unbounded_channel();
+        let watched = files.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                Ok(event) if is_change(&event.kind) => {
+                    if event.paths.iter().any(|p| watched.contains(p)) {
+                        let _ = tx.send(());
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Error watching the todo files: {e}"),
+            })
+            .map_err(|e| watch_error(&files[0], e))?;
+        // Watching the directories rather than the files, which editors often replace
+        for dir in files.iter().filter_map(|f| f.parent()) {
+            watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .map_err(|e| watch_error(dir, e))?;
+        }
+
+        // The sender is kept when there is no webhook, so that receiving just waits forever
+        let (scopes_tx, mut scopes) = unbounded_channel();
+        if let Some(config) = &self.engine.config().webhook {
+            let server = WebhookServer::bind(config).await?;
+            let scopes_tx = scopes_tx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve(scopes_tx).await {
+                    error!("Webhook server stopped: {e}");
+                }
+            });
+        }
+
+        tokio::pin!(shutdown);
+        let mut known: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+        let mut failures = 0;
+        loop {
+            let started = Instant::now();
+            match self.engine.run().await {
+                Ok(outcome) => {
+                    failures = 0;
+                    info!("{} in {:.1?}", summary(&outcome), started.elapsed());
+                    remember(&mut known, outcome)?;
+                }
+                Err(e) => {
+                    failures += 1;
+                    error!("Sync failed ({failures} in a row): {e}");
+                }
+            }
+            let delay = match failures {
+                0 => self.interval,
+                n => (self.interval.saturating_mul(2u32.saturating_pow(n - 1)))
+                    .min(MAX_BACKOFF.max(self.interval)),
+            };
+            debug!("Next sync in {delay:?}");
+
+            let deadline = Instant::now() + delay;
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(deadline) => break,
+                    _ = &mut shutdown => {
+                        info!("Shutting down");
+                        return Ok(());
+                    }
+                    Some(()) = changes.recv() => {
+                        debounce(&mut changes, self.debounce).await;
+                        if changed(&files, &known).await {
+                            info!("Todo file changed, syncing");
+                            break;
+                        }
+                    }
+                    Some(scope) = scopes.recv() => {
+                        // Webhooks often come in bursts, e.g. for an MR and a comment on it
+                        let mut pending = HashSet::from([scope]);
+                        let quiet = self.debounce;
+                        while let Ok(Some(scope)) = tokio::time::timeout(quiet, scopes.recv()).await {
+                            pending.insert(scope);
+                        }
+                        for scope in pending {
+                            self.refresh(&scope, &mut known).await?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn refresh(&self, scope: &Scope, known: &mut HashMap<PathBuf, Vec<u8>>) -> AppResult<()> {
+        let started = Instant::now();
+        match self.engine.refresh(scope).await {
+            Ok(outcome) => {
+                info!(
+                    "Refresh of {} {}: {} in {:.1?}",
+                    scope.project_path,
+                    scope.target_type,
+                    summary(&outcome),
+                    started.elapsed()
+                );
+                remember(known, outcome)
+            }
+            Err(e) => {
+                error!("Refresh of {} failed: {e}", scope.project_path);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Records the content of the files after a sync, see [`changed`]
+fn remember(known: &mut HashMap<PathBuf, Vec<u8>>, outcome: SyncOutcome) -> AppResult<()> {
+    for file in outcome.files {
+        known.insert(absolute(&file.path)?, file.content);
+    }
+    Ok(())
+}
+
+/// Summary of a sync, such as "Synced 2 files (1 written): 3 new, 1 updated, 0 deleted, 1 pushed"
+pub fn summary(outcome: &SyncOutcome) -> String {
+    let (new, upd, del) = outcome.files.iter().fold((0, 0, 0), |acc, f| {
+        let changes = f.changes.counts();
+        (acc.0 + changes.0, acc.1 + changes.1, acc.2 + changes.2)
+    });
+    let written = outcome.files.iter().filter(|f| f.written).count();
+    format!(
+        "Synced {} files ({written} written): {new} new, {upd} updated, {del} deleted, {} pushed",
+        outcome.files.len(),
+        outcome.pushed
+    )
+}
+
+fn is_change(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+    )
+}
+
+/// Waits until no change has been received for `quiet`
+async fn debounce(changes: &mut UnboundedReceiver<()>, quiet: Duration) {
+    while let Ok(Some(())) = tokio::time::timeout(quiet, changes.recv()).await {}
+}
+
+/// Whether a file differs from its content after the last sync, so that the sync's own writes
+/// don't trigger another one
+async fn changed(files: &[PathBuf], known: &HashMap<PathBuf, Vec<u8>>) -> bool {
+    for file in files {
+        let content = tokio::fs::read(file).await.ok();
+        if content.as_ref() != known.get(file) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Absolute path of a file in an existing directory, as reported by the watcher
+fn absolute(path: impl AsRef<Path>) -> AppResult<PathBuf> {
+    let path = path.as_ref();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let dir = std::fs::canonicalize(dir).map_err(|e| IoError::new(dir, e))?;
+    Ok(match path.file_name() {
+        Some(name) => dir.join(name),
+        None => dir,
+    })
+}
+
+fn watch_error(path: &Path, e: notify::Error) -> crate::Error {
+    let source = match e.kind {
+        notify::ErrorKind::Io(e) => e,
+        _ => std::io::Error::other(e.to_string()),
+    };
+    IoError::new(path, source).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_changed() {
+        let dir =
+            std::env::temp_dir().join(format!("gitlab-todotxt-daemon-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let file = absolute(dir.join("todo.txt")).unwrap();
+        tokio::fs::write(&file, "x Done id:1\n").await.unwrap();
+        let files = [file.clone()];
+
+        let mut known = HashMap::new();
+        assert!(changed(&files, &known).await);
+        known.insert(file.clone(), b"x Done id:1\n".to_vec());
+        assert!(!changed(&files, &known).await);
+        tokio::fs::write(&file, "Pending id:1\n").await.unwrap();
+        assert!(changed(&files, &known).await);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+use crate::error::ParseError;
+use crate::todo::{DescriptionPart, Todo, EXTENSION_TAGS};
+use documented::DocumentedFields;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// Suffix of the context given to the lines moved out of the sync by [`Repair::Quarantine`]
+const QUARANTINE_SUFFIX: &str = "quarantine";
+
+/// A problem with the synced lines of a todo file, see [`diagnose`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// Several lines have the same Gitlab id, e.g. after copy-pasting a synced line
+    Duplicate { id: usize, count: usize },
+    /// A line has the context tag but no id, so the sync can't tell which todo it is
+    Orphan { line: String },
+    /// The id of a line isn't a number
+    MalformedId { line: String, id: String },
+    /// The id of a line isn't one of the user's todos: the line was synced with another account,
+    /// or its todo was deleted on Gitlab
+    Unknown { line: String, id: usize },
+}
+
+/// How [`repair`] fixes the lines that can't be synced: orphans, malformed and unknown ids.
+/// Duplicates are always merged
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, DocumentedFields)]
+#[serde(rename_all = "lowercase")]
+pub enum Repair {
+    /// Remove the context tag, making them lines of the user (or the id if there is no context tag)
+    Strip,
+    /// Same as strip, and tag them with @<context>-quarantine at the end of the file for review
+    Quarantine,
+}
+
+/// Finds the problems in the lines of a todo file. Lines are synced if they have the `context`
+/// tag, or all of them if there is none. Ids are only checked against the `known` ones, the
+/// ids of every todo of the user, if given
+pub fn diagnose(
+    todos: &[Todo],
+    context: Option<&str>,
+    known: Option<&HashSet<usize>>,
+) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut ids: Vec<(usize, usize)> = Vec::new();
+    for todo in todos {
+        match check(todo, context, known) {
+            Ok(Some(id)) => match ids.iter_mut().find(|(i, _)| *i == id) {
+                Some((_, count)) => *count += 1,
+                None => ids.push((id, 1)),
+            },
+            Ok(None) => {}
+            Err(problem) => problems.push(problem),
+        }
+    }
+    problems.extend(
+        ids.into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(id, count)| Problem::Duplicate { id, count }),
+    );
+    problems
+}
+
+/// Fixes the problems found by [`diagnose`]. The duplicates of a line are merged into the first
+/// one, which gets their extension tags and is done if any of them is, and the other problematic
+/// lines are fixed as told by `repair`
+pub fn repair(
+    todos: Vec<Todo>,
+    context: Option<&str>,
+    known: Option<&HashSet<usize>>,
+    repair: Repair,
+) -> Vec<Todo> {
+    let mut repaired: Vec<Todo> = Vec::with_capacity(todos.len());
+    let mut quarantined = Vec::new();
+    let mut first: HashMap<usize, usize> = HashMap::new();
+    for mut todo in todos {
+        match check(&todo, context, known) {
+            Ok(Some(id)) => {
+                if let Some(&i) = first.get(&id) {
+                    merge_duplicate(&mut repaired[i], todo);
+                    continue;
+                }
+                first.insert(id, repaired.len());
+            }
+            Ok(None) => {}
+            Err(_) => {
+                match context {
+                    Some(context) => todo.remove_tag(DescriptionPart::Context(context)),
+                    None => todo.remove_data("id"),
+                };
+                if repair == Repair::Quarantine {
+                    todo += DescriptionPart::Context(&quarantine_context(context));
+                    quarantined.push(todo);
+                    continue;
+                }
+            }
+        }
+        repaired.push(todo);
+    }
+    repaired.extend(quarantined);
+    repaired
+}
+
+/// The context of the lines moved out of the sync by [`Repair::Quarantine`]
+pub fn quarantine_context(context: Option<&str>) -> String {
+    match context {
+        Some(context) => format!("{context}-{QUARANTINE_SUFFIX}"),
+        None => QUARANTINE_SUFFIX.into(),
+    }
+}
+
+/// The id of a synced line, `None` for the lines of the user
+fn check(
+    todo: &Todo,
+    context: Option<&str>,
+    known: Option<&HashSet<usize>>,
+) -> Result<Option<usize>, Problem> {
+    if context.is_some_and(|ctx| !todo.has_context(ctx)) {
+        return Ok(None);
+    }
+    let line = || todo.to_string();
+    let Some(id) = todo.get_data("id") else {
+        return match context {
+            Some(_) => Err(Problem::Orphan { line: line() }),
+            None => Ok(None),
+        };
+    };
+    let Ok(id) = id.parse() else {
+        return Err(Problem::MalformedId {
+            line: line(),
+            id: id.into(),
+        });
+    };
+    match known {
+        Some(known) if !known.contains(&id) => Err(Problem::Unknown { line: line(), id }),
+        _ => Ok(Some(id)),
+    }
+}
+
+fn merge_duplicate(todo: &mut Todo, duplicate: Todo) {
+    todo.copy_data_from(&duplicate, &EXTENSION_TAGS);
+    if duplicate.done && !todo.done {
+        todo.done = true;
+        todo.created = duplicate.created;
+        todo.completed = duplicate.completed;
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Duplicate { id, count } => write!(f, "{count} lines have the id {id}"),
+            Problem::Orphan { line } => write!(f, "Synced line without an id: '{line}'"),
+            Problem::MalformedId { line, id } => write!(f, "Invalid id '{id}' in '{line}'"),
+            Problem::Unknown { line, id } => write!(
+                f,
+                "Todo #{id} isn't one of the user's, it was synced with another account or \
+                deleted: '{line}'"
+            ),
+        }
+    }
+}
+
+impl FromStr for Repair {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        match s {
+            "strip" => Ok(Repair::Strip),
+            "quarantine" => Ok(Repair::Quarantine),
+            _ => Err(ParseError::new(format!(
+                "Unknown repair '{s}', expected strip or quarantine"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn todos(lines: &[&str]) -> Vec<Todo> {
+        lines.iter().map(|l| l.parse().unwrap()).collect()
+    }
+
+    fn lines(todos: &[Todo]) -> Vec<String> {
+        todos.iter().map(Todo::to_string).collect()
+    }
+
+    const FILE: [&str; 7] = [
+        "Review id:1 @gitlab",
+        "Call mom",
+        "Review again id:1 due:2024-03-01 @gitlab",
+        "Lost @gitlab",
+        "Typo id:x1 @gitlab",
+        "Other account id:9 @gitlab",
+        "x 2024-02-01 2024-01-01 Review id:1 @gitlab",
+    ];
+
+    #[test]
+    fn test_diagnose() {
+        let file = todos(&FILE);
+        let known = HashSet::from([1]);
+        assert_eq!(
+            diagnose(&file, Some("gitlab"), Some(&known)),
+            [
+                Problem::Orphan {
+                    line: FILE[3].into()
+                },
+                Problem::MalformedId {
+                    line: FILE[4].into(),
+                    id: "x1".into()
+                },
+                Problem::Unknown {
+                    line: FILE[5].into(),
+                    id: 9
+                },
+                Problem::Duplicate { id: 1, count: 3 },
+            ]
+        );
+        assert_eq!(
+            diagnose(&file, Some("gitlab"), None)[2],
+            Problem::Duplicate { id: 1, count: 3 }
+        );
+        let problems = diagnose(&file, None, None);
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(diagnose(&file[..2], Some("gitlab"), None).is_empty());
+    }
+
+    #[test]
+    fn test_repair() {
+        let known = HashSet::from([1]);
+        let repaired = repair(todos(&FILE), Some("gitlab"), Some(&known), Repair::Strip);
+        assert_eq!(
+            lines(&repaired),
+            [
+                "x 2024-02-01 2024-01-01 Review id:1 @gitlab due:2024-03-01",
+                "Call mom",
+                "Lost",
+                "Typo id:x1",
+                "Other account id:9",
+            ]
+        );
+        assert!(diagnose(&repaired, Some("gitlab"), Some(&known)).is_empty());
+
+        let repaired = repair(todos(&FILE), Some("gitlab"), None, Repair::Quarantine);
+        assert_eq!(
+            lines(&repaired),
+            [
+                "x 2024-02-01 2024-01-01 Review id:1 @gitlab due:2024-03-01",
+                "Call mom",
+                "Other account id:9 @gitlab",
+                "Lost @gitlab-quarantine",
+                "Typo id:x1 @gitlab-quarantine",
+            ]
+        );
+
+        let repaired = repair(todos(&FILE[4..6]), None, Some(&known), Repair::Quarantine);
+        assert_eq!(
+            lines(&repaired),
+            [
+                "Typo @gitlab @quarantine",
+                "Other account @gitlab @quarantine"
+            ]
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use reqwest::StatusCode;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use thiserror::Error as ThisError;
+
+/// Errors returned by this crate
+#[derive(Debug, ThisError)]
+pub enum Error {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Api(#[from] ApiError),
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error(transparent)]
+    Io(#[from] IoError),
+    #[error("Sync aborted: {0}{hint}", hint = .0.hint())]
+    Aborted(#[from] AbortReason),
+}
+
+pub type AppResult<T> = Result<T, Error>;
+
+/// The configuration couldn't be loaded, or is invalid
+#[derive(Debug, ThisError)]
+pub enum ConfigError {
+    #[error("Couldn't read config file {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid config file {}: {source}", path.display())]
+    Invalid {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("Invalid Gitlab URL: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("{0}")]
+    Other(String),
+}
+
+/// A request to the Gitlab API failed
+#[derive(Debug, ThisError)]
+pub enum ApiError {
+    /// The request couldn't be sent, or the response couldn't be received
+    #[error("Request to {url} failed: {source}")]
+    Request { url: String, source: reqwest::Error },
+    /// The server answered with an error status, `message` being the error reported by Gitlab
+    #[error("{url} returned {status}: {message}{}", hint(*status))]
+    Status {
+        url: String,
+        status: StatusCode,
+        message: String,
+    },
+    /// A paginated list didn't contain as many items as announced
+    #[error("Incomplete list from {url}: expected {expected} items, received {received}")]
+    Incomplete {
+        url: String,
+        expected: usize,
+        received: usize,
+    },
+    /// The response body isn't what was expected
+    #[error(
+        "Invalid response from {url}: {source}\n\
+        Check that gitlab_host is the base URL of your Gitlab instance"
+    )]
+    Decode {
+        url: String,
+        source: serde_json::Error,
+    },
+}
+
+/// A safety check stopped the sync before it changed the todo file
+#[derive(Debug, ThisError)]
+pub enum AbortReason {
+    #[error("it would delete {deleted} of the {synced} synced todos, more than {limit}")]
+    TooManyDeletions {
+        deleted: usize,
+        synced: usize,
+        limit: String,
+    },
+    #[error("the Gitlab token belongs to {current}, but {expected}")]
+    UserMismatch { current: String, expected: String },
+    #[error("the {hook} hook failed ({status})")]
+    Vetoed { hook: String, status: String },
+}
+
+impl AbortReason {
+    /// How to get past the abort: `--force` overrides the safety checks, but not hooks
+    fn hint(&self) -> &'static str {
+        match self {
+            AbortReason::Vetoed { .. } => "",
+            _ => ". Run with --force to apply it anyway",
+        }
+    }
+}
+
+/// Some text, usually a line of the todo file, couldn't be parsed
+#[derive(Debug, ThisError)]
+pub struct ParseError {
+    /// Line number in the parsed file, starting at 1
+    pub line: Option<usize>,
+    /// Column in the parsed line, in characters starting at 1
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+/// Reading or writing a file failed
+#[derive(Debug, ThisError)]
+pub struct IoError {
+    pub path: Option<PathBuf>,
+    #[source]
+    pub source: std::io::Error,
+}
+
+impl Error {
+    /// Process exit code for this error, following the conventions of `sysexits.h`
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Config(_) => 78,                     // EX_CONFIG
+            Error::Api(e) if e.is_auth_failure() => 77, // EX_NOPERM
+            Error::Api(_) => 69,                        // EX_UNAVAILABLE
+            Error::Parse(_) => 65,                      // EX_DATAERR
+            Error::Io(_) => 74,                         // EX_IOERR
+            Error::Aborted(_) => 75,                    // EX_TEMPFAIL
+        }
+    }
+}
+
+impl Error {
+    /// Attaches a path to I/O errors that don't have one yet
+    pub fn with_path(self, path: impl AsRef<Path>) -> Self {
+        match self {
+            Error::Io(IoError { path: None, source }) => IoError::new(path, source).into(),
+            e => e,
+        }
+    }
+}
+
+impl ApiError {
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ApiError::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Whether the server rejected the token, or its permissions
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(
+            self.status(),
+            Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+        )
+    }
+}
+
+/// Guidance on how to fix the most common API errors
+fn hint(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::UNAUTHORIZED => {
+            "\nThe Gitlab token is invalid, expired or revoked: create a new personal access token \
+            and set it as gitlab_token"
+        }
+        StatusCode::FORBIDDEN => {
+            "\nThe Gitlab token doesn't have the required scope: it needs at least read_api, or \
+            api to mark todos as done"
+        }
+        StatusCode::NOT_FOUND => {
+            "\nCheck that gitlab_host is the base URL of your Gitlab instance \
+            (e.g. https://gitlab.example.com), without /api/v4 or a project path"
+        }
+        _ => "",
+    }
+}
+
+impl ParseError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            line: None,
+            column: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn at_line(mut self, line: usize) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    /// Sets the column from the byte offset of the error in `text`
+    pub fn at_offset(mut self, text: &str, offset: usize) -> Self {
+        self.column = Some(text[..offset].chars().count() + 1);
+        self
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (self.line, self.column) {
+            (Some(l), Some(c)) => write!(f, "line {l}, column {c}: ")?,
+            (Some(l), None) => write!(f, "line {l}: ")?,
+            (None, Some(c)) => write!(f, "column {c}: ")?,
+            (None, None) => {}
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl IoError {
+    pub fn new(path: impl AsRef<Path>, source: std::io::Error) -> Self {
+        Self {
+            path: Some(path.as_ref().to_owned()),
+            source,
+        }
+    }
+}
+
+impl Display for IoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}: {}", path.display(), self.source),
+            None => write!(f, "{}", self.source),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(source: std::io::Error) -> Self {
+        Error::Io(IoError { path: None, source })
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(source: reqwest::Error) -> Self {
+        let url = source.url().map(|u| u.to_string()).unwrap_or_default();
+        Error::Api(ApiError::Request { url, source })
+    }
+}
+
+impl From<serde_json::Error> for ParseError {
+    fn from(e: serde_json::Error) -> Self {
+        let message = e.to_string();
+        let location = format!(" at line {} column {}", e.line(), e.column());
+        Self {
+            line: Some(e.line()),
+            column: Some(e.column()),
+            message: message.strip_suffix(&location).unwrap_or(&message).into(),
+        }
+    }
+}
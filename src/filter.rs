@@ -0,0 +1,177 @@
+use crate::gitlab::GitlabTodo;
+use documented::DocumentedFields;
+use regex::Regex;
+use serde::de::Error as SerdeError;
+use serde::{Deserialize, Deserializer};
+use std::fmt::{Display, Formatter};
+
+/// Which Gitlab todos are synced, see [`AppConfig::filters`]
+///
+/// [`AppConfig::filters`]: crate::config::AppConfig::filters
+#[derive(Deserialize, Clone, Debug, Default, DocumentedFields)]
+#[serde(deny_unknown_fields)]
+pub struct Filters {
+    /// Only sync todos matching at least one of these filters. All todos are synced if empty
+    #[serde(default)]
+    pub include: Vec<TodoFilter>,
+    /// Never sync todos matching one of these filters
+    #[serde(default)]
+    pub exclude: Vec<TodoFilter>,
+}
+
+/// Matches the todos for which every given criterion matches
+#[derive(Deserialize, Clone, Debug, Default, DocumentedFields)]
+#[serde(deny_unknown_fields)]
+pub struct TodoFilter {
+    /// Full path of the project, e.g. "my-group/*"
+    pub project: Option<Glob>,
+    /// Full path of the group, or of one of the project's parent groups
+    pub group: Option<Glob>,
+    /// Type of the target, e.g. "MergeRequest" or "Issue"
+    pub target_type: Option<Glob>,
+    /// Action that created the todo, e.g. "review_requested", "assigned" or "mentioned"
+    pub action: Option<Glob>,
+    /// Username of the author, e.g. "*-bot"
+    pub author: Option<Glob>,
+}
+
+/// A pattern where `*` matches any text without `/`, `**` any text, and `?` any character
+#[derive(Clone, Debug)]
+pub struct Glob {
+    pattern: String,
+    regex: Regex,
+}
+
+impl Filters {
+    /// Whether a todo should be synced
+    pub fn matches(&self, todo: &GitlabTodo) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|f| f.matches(todo)))
+            && !self.exclude.iter().any(|f| f.matches(todo))
+    }
+
+    /// The filter that every synced todo matches, if there is a single include filter. The
+    /// literal criteria of this filter can be applied by the Gitlab API
+    pub fn server_side(&self) -> Option<&TodoFilter> {
+        match self.include.as_slice() {
+            [filter] => Some(filter),
+            _ => None,
+        }
+    }
+}
+
+impl TodoFilter {
+    pub fn matches(&self, todo: &GitlabTodo) -> bool {
+        fn check(glob: &Option<Glob>, value: Option<&str>) -> bool {
+            match (glob, value) {
+                (None, _) => true,
+                (Some(glob), Some(value)) => glob.is_match(value),
+                (Some(_), None) => false,
+            }
+        }
+        let groups = || {
+            let parents = todo
+                .project
+                .iter()
+                .flat_map(|path| path.match_indices('/').map(|(i, _)| &path[..i]));
+            todo.group.as_deref().into_iter().chain(parents)
+        };
+        check(&self.project, todo.project.as_deref())
+            && (self.group.is_none() || groups().any(|g| check(&self.group, Some(g))))
+            && check(&self.target_type, Some(&todo.target_type))
+            && check(&self.action, Some(&todo.action_name))
+            && check(&self.author, todo.author.as_deref())
+    }
+}
+
+impl Glob {
+    pub fn new(pattern: impl Into<String>) -> Result<Self, regex::Error> {
+        let pattern = pattern.into();
+        let mut regex = String::from("^");
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.next_if_eq(&'*').is_some() => regex.push_str(".*"),
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push('.'),
+                c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        regex.push('$');
+        Ok(Self {
+            regex: Regex::new(&regex)?,
+            pattern,
+        })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.regex.is_match(text)
+    }
+
+    /// The pattern if it doesn't contain any wildcard, i.e. only matches itself
+    pub fn literal(&self) -> Option<&str> {
+        (!self.pattern.contains(['*', '?'])).then_some(&self.pattern)
+    }
+}
+
+impl Display for Glob {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+impl<'de> Deserialize<'de> for Glob {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(de)?;
+        Glob::new(pattern).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn todo(project: &str, action: &str, author: &str) -> GitlabTodo {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "body": "Body",
+            "state": "pending",
+            "created_at": "2024-01-01T00:00:00.000Z",
+            "updated_at": "2024-01-01T00:00:00.000Z",
+            "action_name": action,
+            "target_type": "MergeRequest",
+            "author": {"username": author},
+            "project": {"path_with_namespace": project},
+            "target_url": "https://git.example/a/b/-/merge_requests/1",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_glob() {
+        let glob = Glob::new("main/*").unwrap();
+        assert!(glob.is_match("main/app"));
+        assert!(!glob.is_match("main/sub/app"));
+        assert!(!glob.is_match("other/main/app"));
+        assert!(Glob::new("main/**").unwrap().is_match("main/sub/app"));
+        assert!(Glob::new("*-bot").unwrap().is_match("renovate-bot"));
+        assert!(Glob::new("v?.0").unwrap().is_match("v1.0"));
+        assert!(!Glob::new("v1.0").unwrap().is_match("v1x0"));
+        assert_eq!(Glob::new("main").unwrap().literal(), Some("main"));
+        assert_eq!(Glob::new("ma*").unwrap().literal(), None);
+    }
+
+    #[test]
+    fn test_filters() {
+        let filters: Filters = serde_json::from_value(serde_json::json!({
+            "include": [{"group": "main", "action": "review_requested"}],
+            "exclude": [{"author": "*-bot"}],
+        }))
+        .unwrap();
+        assert!(filters.matches(&todo("main/sub/app", "review_requested", "jdoe")));
+        assert!(!filters.matches(&todo("main/app", "review_requested", "renovate-bot")));
+        assert!(!filters.matches(&todo("main/app", "mentioned", "jdoe")));
+        assert!(!filters.matches(&todo("other/app", "review_requested", "jdoe")));
+        assert!(filters.server_side().is_some());
+        assert!(Filters::default().matches(&todo("other/app", "mentioned", "renovate-bot")));
+    }
+}
@@ -1,33 +1,168 @@
+use crate::body;
 use crate::config::{AppConfig, SecretString};
+use crate::error::{ApiError, AppResult, ConfigError, ParseError};
+use crate::session::{Exchange, Session};
 use crate::todo::{Date, DescriptionPart, Todo};
-use crate::{AppResult, Error};
-use reqwest::{IntoUrl, Method, RequestBuilder};
-use serde::de::Error as SerdeError;
-use serde::Deserialize;
+use futures::future::try_join_all;
+use log::*;
+use rand::Rng;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ETAG, IF_NONE_MATCH};
+use reqwest::{IntoUrl, Method, Request, RequestBuilder, StatusCode};
+use serde::de::{DeserializeOwned, Error as SerdeError};
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Semaphore;
 use url::Url;
 
 const API_BASE: &str = "api/v4/";
 const STATE_PENDING: &str = "pending";
 const STATE_DONE: &str = "done";
+const PER_PAGE: &str = "100";
+/// Headers of a response that are stored along with its body in the [`ResponseCache`]
+const CACHED_HEADERS: [&str; 3] = ["x-next-page", "x-total", "x-total-pages"];
 
+/// Client for the parts of the Gitlab REST API used by the sync.
+///
+/// Clients share their connection pool and their limit of concurrent requests with every other
+/// client for the same host, including clones.
 #[derive(Debug, Clone)]
 pub struct GitlabAPI {
     client: reqwest::Client,
+    /// Permits to send a request to the host, see [`ApiOptions::max_concurrent_requests`]
+    limiter: Arc<Semaphore>,
     base: Url,
     token: SecretString,
+    options: ApiOptions,
+    /// Time before which no request should be sent, set when the rate limit has been exhausted
+    not_before: Arc<Mutex<Option<Instant>>>,
+    cache: Arc<Mutex<CacheState>>,
+    session: Option<Arc<Session>>,
+}
+
+/// Network settings of a [`GitlabAPI`]
+#[derive(Debug, Clone)]
+pub struct ApiOptions {
+    pub connect_timeout: Duration,
+    /// Maximum time to wait for data while receiving a response
+    pub read_timeout: Duration,
+    /// Number of times a request is retried after a transient failure: connection error,
+    /// timeout, 429 or 5xx status
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each following one
+    pub retry_delay: Duration,
+    /// Upper bound for the delay before a retry. Requests for which the server asks to wait
+    /// longer than that fail instead
+    pub max_retry_delay: Duration,
+    /// Maximum number of requests sent at the same time to the host. It is shared by the
+    /// clients for that host, and set by the first one created
+    pub max_concurrent_requests: usize,
+}
+
+/// Responses to GET requests indexed by URL, used to make conditional requests. Gitlab answers
+/// them with `304 Not Modified` when the resource didn't change, and the cached body is used
+/// instead.
+pub type ResponseCache = HashMap<String, CachedResponse>;
+
+/// A response stored in a [`ResponseCache`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub etag: String,
+    /// Headers needed to use the response again, such as the pagination ones
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    /// Responses given to [`GitlabAPI::set_response_cache`]
+    previous: ResponseCache,
+    /// Responses received or reused since then
+    current: ResponseCache,
+}
+
+/// Filters of the todos API, see [`Filters::server_side`]
+///
+/// [`Filters::server_side`]: crate::filter::Filters::server_side
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TodoQuery {
+    pub action: Option<String>,
+    pub author_id: Option<u64>,
+    pub project_id: Option<u64>,
+    pub group_id: Option<u64>,
+    pub target_type: Option<String>,
+}
+
+/// A page of a list endpoint, along with the pagination headers of its response
+struct Page<T> {
+    items: Vec<T>,
+    next: Option<usize>,
+    total: Option<usize>,
+    /// Missing for very large lists, Gitlab not counting them
+    total_pages: Option<usize>,
+}
+
+/// A complete response, whose status hasn't been checked yet
+struct ApiResponse {
+    url: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
 }
 
-#[allow(dead_code)]
 impl GitlabAPI {
-    pub fn new(base: Url, token: impl AsRef<str>) -> Result<Self, url::ParseError> {
+    /// Creates a client for the Gitlab instance at `base`, authenticating with a personal access
+    /// token
+    pub fn new(base: Url, token: impl AsRef<str>) -> AppResult<Self> {
+        Self::with_options(base, token, ApiOptions::default())
+    }
+
+    pub fn with_options(
+        mut base: Url,
+        token: impl AsRef<str>,
+        options: ApiOptions,
+    ) -> AppResult<Self> {
+        if !base.path().ends_with('/') {
+            // Otherwise joining would replace the last segment of instances under a subpath
+            base.set_path(&format!("{}/", base.path()));
+        }
         Ok(Self {
-            client: reqwest::Client::new(),
-            base: base.join(API_BASE)?,
+            client: shared_client(&options)?,
+            limiter: host_limiter(&base, options.max_concurrent_requests),
+            base: base.join(API_BASE).map_err(ConfigError::from)?,
             token: SecretString(token.as_ref().to_owned()),
+            options,
+            not_before: Default::default(),
+            cache: Default::default(),
+            session: None,
         })
     }
 
+    /// Records the exchanges with the server, or replays recorded ones instead of sending the
+    /// requests. The response cache isn't used then, so that recordings are self-contained
+    pub fn with_session(mut self, session: Session) -> Self {
+        self.session = Some(Arc::new(session));
+        self
+    }
+
+    /// Sets the responses used to make conditional requests, usually those returned by
+    /// [`Self::response_cache`] in a previous run
+    pub fn set_response_cache(&self, cache: ResponseCache) {
+        *self.cache.lock().unwrap() = CacheState {
+            previous: cache,
+            current: Default::default(),
+        };
+    }
+
+    /// Responses that can be used for conditional requests: the ones received or reused since
+    /// the last call to [`Self::set_response_cache`], so that stale entries are dropped
+    pub fn response_cache(&self) -> ResponseCache {
+        self.cache.lock().unwrap().current.clone()
+    }
+
     fn request(&self, method: Method, u: impl IntoUrl) -> RequestBuilder {
         const AUTH_HEADER: &str = "PRIVATE-TOKEN";
         self.client
@@ -39,32 +174,550 @@ impl GitlabAPI {
         self.request(Method::GET, u)
     }
 
-    async fn get_todos(&self, pending: bool) -> reqwest::Result<Vec<GitlabTodo>> {
+    fn todos_url(&self) -> Url {
         const TODO_ENDPOINT: &str = "todos/";
-        let pending = if pending { STATE_PENDING } else { STATE_DONE };
-        let url = self.base.join(TODO_ENDPOINT).unwrap();
-        let request = self.get(url.clone()).query(&[("state", pending)]);
-        print!("GET {url} -> ");
-        let response = request.send().await?;
-        println!("{response:?}");
-        response.json().await
+        self.base.join(TODO_ENDPOINT).unwrap()
+    }
+
+    /// Fetches every page of a list endpoint, the ones after the first concurrently. Fails if
+    /// any page can't be fetched, or if the number of items doesn't match the total announced by
+    /// the server (e.g. because the list changed while it was being fetched), so that the result
+    /// is never silently incomplete
+    async fn get_paginated<T: DeserializeOwned>(
+        &self,
+        url: &Url,
+        query: &[(&str, String)],
+    ) -> AppResult<Vec<T>> {
+        let first = self.get_page(url, query, 1).await?;
+        let Some(total_pages) = first.total_pages else {
+            return self.follow_pages(url, query, first, |_| false).await;
+        };
+        let pages = try_join_all((2..=total_pages).map(|page| self.get_page(url, query, page)));
+        let mut items = first.items;
+        for mut page in pages.await? {
+            if page.total != first.total {
+                return Err(incomplete(url, first.total, page.total));
+            }
+            items.append(&mut page.items);
+        }
+        check_total(url, first.total, items)
+    }
+
+    /// Fetches the pages of a list endpoint one after the other, until `stop` returns true for
+    /// the last fetched one. Like [`Self::get_paginated`], fails if a complete list doesn't have
+    /// the announced number of items
+    async fn get_paginated_until<T: DeserializeOwned>(
+        &self,
+        url: &Url,
+        query: &[(&str, String)],
+        stop: impl Fn(&[T]) -> bool,
+    ) -> AppResult<Vec<T>> {
+        let first = self.get_page(url, query, 1).await?;
+        self.follow_pages(url, query, first, stop).await
+    }
+
+    async fn follow_pages<T: DeserializeOwned>(
+        &self,
+        url: &Url,
+        query: &[(&str, String)],
+        mut page: Page<T>,
+        stop: impl Fn(&[T]) -> bool,
+    ) -> AppResult<Vec<T>> {
+        let total = page.total;
+        let mut items = Vec::new();
+        loop {
+            let stopped = stop(&page.items);
+            items.append(&mut page.items);
+            match page.next {
+                Some(_) if stopped => return Ok(items),
+                Some(next) => page = self.get_page(url, query, next).await?,
+                None => return check_total(url, total, items),
+            }
+        }
+    }
+
+    async fn get_page<T: DeserializeOwned>(
+        &self,
+        url: &Url,
+        query: &[(&str, String)],
+        page: usize,
+    ) -> AppResult<Page<T>> {
+        let request = self
+            .get(url.clone())
+            .query(query)
+            .query(&[("per_page", PER_PAGE), ("page", &page.to_string())]);
+        let response = self.execute(request).await?;
+        let number = |name| header(&response.headers, name).and_then(|v| v.parse().ok());
+        Ok(Page {
+            next: number("x-next-page"),
+            total: number("x-total"),
+            total_pages: number("x-total-pages"),
+            items: response.json()?,
+        })
+    }
+
+    /// Sends a request, retrying on transient failures, and checks the status of its response.
+    /// GET requests are made conditional when a response to the same URL is cached
+    async fn execute(&self, request: RequestBuilder) -> AppResult<ApiResponse> {
+        let mut request = request.build()?;
+        let key = request.url().to_string();
+        let cacheable = request.method() == Method::GET && self.session.is_none();
+        let cached = cacheable
+            .then(|| {
+                let cache = self.cache.lock().unwrap();
+                cache
+                    .current
+                    .get(&key)
+                    .or(cache.previous.get(&key))
+                    .cloned()
+            })
+            .flatten();
+        if let Some(etag) = cached.as_ref().and_then(|c| c.etag.parse().ok()) {
+            request.headers_mut().insert(IF_NONE_MATCH, etag);
+        }
+
+        let response = self.send(request).await?;
+        let response = match cached {
+            Some(cached) if response.status == StatusCode::NOT_MODIFIED => {
+                debug!("{key} not modified, using cached response");
+                ApiResponse::from_cache(response.url, cached)
+            }
+            _ => response,
+        };
+        if cacheable && response.status.is_success() {
+            if let Some(cached) = response.to_cache() {
+                self.cache.lock().unwrap().current.insert(key, cached);
+            }
+        }
+        response.check_status()
+    }
+
+    /// Sends a request, retrying on transient failures
+    async fn send(&self, request: Request) -> AppResult<ApiResponse> {
+        let mut attempt = 0;
+        loop {
+            self.wait_for_rate_limit().await;
+            let req = request
+                .try_clone()
+                .expect("API requests don't have streaming bodies");
+            let result = {
+                let _permit = self
+                    .limiter
+                    .acquire()
+                    .await
+                    .expect("The limiter is never closed");
+                self.attempt(req).await
+            };
+            let server_delay = match &result {
+                Ok(response) => {
+                    self.update_rate_limit(&response.headers);
+                    let status = response.status;
+                    (status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error())
+                        .then(|| retry_after(&response.headers))
+                }
+                Err(e) => (e.is_connect() || e.is_timeout() || e.is_request() || e.is_body())
+                    .then_some(None),
+            };
+            let delay = match server_delay {
+                Some(Some(delay)) if delay <= self.options.max_retry_delay => Some(delay),
+                Some(Some(_)) => None,
+                Some(None) => Some(self.backoff(attempt)),
+                None => None,
+            };
+            match delay {
+                Some(delay) if attempt < self.options.max_retries => {
+                    match &result {
+                        Ok(r) => warn!("{} returned {}, retrying in {delay:?}", r.url, r.status),
+                        Err(e) => warn!("{e}, retrying in {delay:?}"),
+                    }
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                _ => return Ok(result?),
+            }
+        }
+    }
+
+    async fn attempt(&self, request: Request) -> reqwest::Result<ApiResponse> {
+        let method = request.method().to_string();
+        let path = request.url()[url::Position::BeforePath..].to_string();
+        let url = request.url().to_string();
+        if let Some(replayed) = self
+            .session
+            .as_ref()
+            .and_then(|s| s.replayed(&method, &path))
+        {
+            debug!("Replaying {method} {path}");
+            return Ok(ApiResponse::replayed(url, &method, &path, replayed));
+        }
+
+        let response = self.client.execute(request).await?;
+        let url = response.url().to_string();
+        let status = response.status();
+        let headers = response.headers().clone();
+        debug!("{url} -> {status}");
+        let body = response.bytes().await?.to_vec();
+        let response = ApiResponse {
+            url,
+            status,
+            headers,
+            body,
+        };
+        if let Some(session) = &self.session {
+            session
+                .record_exchange(response.exchange(&method, &path))
+                .await;
+        }
+        Ok(response)
+    }
+
+    /// Exponential backoff with jitter: a random delay between half and all of
+    /// `retry_delay * 2^attempt`, capped to `max_retry_delay`
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .options
+            .retry_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.options.max_retry_delay);
+        delay / 2 + delay.mul_f64(rand::thread_rng().gen_range(0.0..0.5))
+    }
+
+    async fn wait_for_rate_limit(&self) {
+        let not_before = *self.not_before.lock().unwrap();
+        if let Some(wait) = not_before.and_then(|t| t.checked_duration_since(Instant::now())) {
+            info!("Rate limit reached, waiting {wait:?}");
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Delays the next requests until the rate limit is reset, if it has been exhausted
+    fn update_rate_limit(&self, headers: &HeaderMap) {
+        let exhausted = header(headers, "ratelimit-remaining") == Some("0");
+        if let Some(wait) = rate_limit_reset(headers).filter(|_| exhausted) {
+            let wait = wait.min(self.options.max_retry_delay);
+            *self.not_before.lock().unwrap() = Some(Instant::now() + wait);
+        }
+    }
+
+    /// Fetches the user the token belongs to
+    pub async fn get_current_user(&self) -> AppResult<GitlabUser> {
+        const USER_ENDPOINT: &str = "user";
+        let url = self.base.join(USER_ENDPOINT).unwrap();
+        self.execute(self.get(url)).await?.json()
+    }
+
+    /// Marks a pending todo as done, returning its updated version
+    pub async fn mark_todo_as_done(&self, id: usize) -> AppResult<GitlabTodo> {
+        let url = self
+            .todos_url()
+            .join(&format!("{id}/mark_as_done"))
+            .unwrap();
+        self.execute(self.request(Method::POST, url)).await?.json()
+    }
+
+    /// Fetches the id of a project from its full path
+    pub async fn get_project_id(&self, path: &str) -> AppResult<u64> {
+        self.get_entity_id("projects", path).await
+    }
+
+    /// Fetches the id of a group from its full path
+    pub async fn get_group_id(&self, path: &str) -> AppResult<u64> {
+        self.get_entity_id("groups", path).await
+    }
+
+    async fn get_entity_id(&self, endpoint: &str, path: &str) -> AppResult<u64> {
+        #[derive(Deserialize)]
+        struct Entity {
+            id: u64,
+        }
+        let mut url = self.base.clone();
+        // Pushing the path as a single segment encodes its slashes, as expected by the API
+        url.path_segments_mut()
+            .unwrap()
+            .pop_if_empty()
+            .push(endpoint)
+            .push(path);
+        Ok(self.execute(self.get(url)).await?.json::<Entity>()?.id)
+    }
+
+    /// Fetches the user with the given username, if it exists
+    pub async fn get_user(&self, username: &str) -> AppResult<Option<GitlabUser>> {
+        const USERS_ENDPOINT: &str = "users";
+        let url = self.base.join(USERS_ENDPOINT).unwrap();
+        let request = self.get(url).query(&[("username", username)]);
+        let users: Vec<GitlabUser> = self.execute(request).await?.json()?;
+        Ok(users.into_iter().next())
+    }
+
+    pub async fn get_pending_todos(&self, query: &TodoQuery) -> AppResult<Vec<GitlabTodo>> {
+        self.get_paginated(&self.todos_url(), &query.params(STATE_PENDING))
+            .await
+    }
+
+    pub async fn get_done_todos(&self, query: &TodoQuery) -> AppResult<Vec<GitlabTodo>> {
+        self.get_paginated(&self.todos_url(), &query.params(STATE_DONE))
+            .await
+    }
+
+    /// Fetches the done todos whose id is at least `min_id`.
+    ///
+    /// The todos API can't filter by update date, but lists todos by decreasing id: pages are
+    /// fetched until one reaches `min_id`, which is usually the first one.
+    pub async fn get_done_todos_since(
+        &self,
+        query: &TodoQuery,
+        min_id: usize,
+    ) -> AppResult<Vec<GitlabTodo>> {
+        let stop = |page: &[GitlabTodo]| page.iter().any(|t| t.id <= min_id);
+        let mut todos = self
+            .get_paginated_until(&self.todos_url(), &query.params(STATE_DONE), stop)
+            .await?;
+        todos.retain(|t| t.id >= min_id);
+        Ok(todos)
     }
 
-    pub async fn get_pending_todos(&self) -> reqwest::Result<Vec<GitlabTodo>> {
-        self.get_todos(true).await
+    pub async fn get_all_todos(&self, query: &TodoQuery) -> AppResult<Vec<GitlabTodo>> {
+        let (pending, done) =
+            tokio::try_join!(self.get_pending_todos(query), self.get_done_todos(query))?;
+        Ok([pending, done].concat())
+    }
+}
+
+impl TodoQuery {
+    fn params(&self, state: &str) -> Vec<(&'static str, String)> {
+        let mut params = vec![("state", state.to_string())];
+        params.extend(self.action.clone().map(|a| ("action", a)));
+        params.extend(self.author_id.map(|id| ("author_id", id.to_string())));
+        params.extend(self.project_id.map(|id| ("project_id", id.to_string())));
+        params.extend(self.group_id.map(|id| ("group_id", id.to_string())));
+        params.extend(self.target_type.clone().map(|t| ("type", t)));
+        params
+    }
+}
+
+impl Default for ApiOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            max_retries: 3,
+            retry_delay: Duration::from_secs(1),
+            max_retry_delay: Duration::from_secs(60),
+            max_concurrent_requests: 4,
+        }
+    }
+}
+
+impl ApiResponse {
+    fn check_status(self) -> AppResult<Self> {
+        if self.status.is_success() {
+            return Ok(self);
+        }
+        trace!(
+            "Error response body: {}",
+            String::from_utf8_lossy(&self.body)
+        );
+        Err(ApiError::Status {
+            url: self.url,
+            status: self.status,
+            message: error_message(&self.body),
+        }
+        .into())
+    }
+
+    fn from_cache(url: String, cached: CachedResponse) -> Self {
+        let headers = cached
+            .headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((
+                    HeaderName::from_bytes(name.as_bytes()).ok()?,
+                    HeaderValue::from_str(value).ok()?,
+                ))
+            })
+            .collect();
+        Self {
+            url,
+            status: StatusCode::OK,
+            headers,
+            body: cached.body.into_bytes(),
+        }
+    }
+
+    /// The response recorded for a request, or a 404 if there is none
+    fn replayed(url: String, method: &str, path: &str, exchange: Option<Exchange>) -> Self {
+        let exchange = exchange.unwrap_or_else(|| {
+            let message = format!("No recorded response to {method} {path}");
+            let body = serde_json::json!({ "message": message }).to_string();
+            Exchange::new(method, path, 404, Vec::new(), body.as_bytes())
+        });
+        let headers = exchange
+            .headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((
+                    HeaderName::from_bytes(name.as_bytes()).ok()?,
+                    HeaderValue::from_str(value).ok()?,
+                ))
+            })
+            .collect();
+        Self {
+            url,
+            status: StatusCode::from_u16(exchange.status).unwrap_or(StatusCode::NOT_FOUND),
+            headers,
+            body: exchange.body(),
+        }
     }
 
-    pub async fn get_done_todos(&self) -> reqwest::Result<Vec<GitlabTodo>> {
-        self.get_todos(false).await
+    fn exchange(&self, method: &str, path: &str) -> Exchange {
+        let headers = self
+            .headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        Exchange::new(method, path, self.status.as_u16(), headers, &self.body)
     }
 
-    pub async fn get_all_todos(&self) -> reqwest::Result<Vec<GitlabTodo>> {
-        Ok([self.get_todos(true).await?, self.get_todos(false).await?].concat())
+    /// The response to store in the [`ResponseCache`], if it has an ETag
+    fn to_cache(&self) -> Option<CachedResponse> {
+        Some(CachedResponse {
+            etag: header(&self.headers, ETAG.as_str())?.to_string(),
+            headers: CACHED_HEADERS
+                .iter()
+                .filter_map(|&name| Some((name.to_string(), header(&self.headers, name)?.into())))
+                .collect(),
+            body: String::from_utf8(self.body.clone()).ok()?,
+        })
+    }
+
+    fn json<T: DeserializeOwned>(&self) -> AppResult<T> {
+        serde_json::from_slice(&self.body).map_err(|source| {
+            ApiError::Decode {
+                url: self.url.clone(),
+                source,
+            }
+            .into()
+        })
+    }
+}
+
+/// HTTP client for the given options, shared by the [`GitlabAPI`]s using the same timeouts so
+/// that they reuse connections
+fn shared_client(options: &ApiOptions) -> AppResult<reqwest::Client> {
+    static CLIENTS: OnceLock<Mutex<HashMap<(Duration, Duration), reqwest::Client>>> =
+        OnceLock::new();
+    let mut clients = CLIENTS.get_or_init(Default::default).lock().unwrap();
+    let key = (options.connect_timeout, options.read_timeout);
+    if let Some(client) = clients.get(&key) {
+        return Ok(client.clone());
+    }
+    let client = reqwest::Client::builder()
+        .connect_timeout(options.connect_timeout)
+        .read_timeout(options.read_timeout)
+        .build()?;
+    clients.insert(key, client.clone());
+    Ok(client)
+}
+
+/// Semaphore limiting the number of concurrent requests to the host of `base`, shared by the
+/// [`GitlabAPI`]s for that host
+fn host_limiter(base: &Url, permits: usize) -> Arc<Semaphore> {
+    static LIMITERS: OnceLock<Mutex<HashMap<String, Arc<Semaphore>>>> = OnceLock::new();
+    let host = base[..url::Position::BeforePath].to_string();
+    LIMITERS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(host)
+        .or_insert_with(|| Arc::new(Semaphore::new(permits.max(1))))
+        .clone()
+}
+
+fn incomplete(url: &Url, expected: Option<usize>, received: Option<usize>) -> crate::Error {
+    ApiError::Incomplete {
+        url: url.to_string(),
+        expected: expected.unwrap_or_default(),
+        received: received.unwrap_or_default(),
+    }
+    .into()
+}
+
+fn check_total<T>(url: &Url, total: Option<usize>, items: Vec<T>) -> AppResult<Vec<T>> {
+    match total {
+        Some(expected) if expected != items.len() => Err(incomplete(url, total, Some(items.len()))),
+        _ => Ok(items),
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+}
+
+/// Delay requested by the server before retrying, from the `Retry-After` header (in seconds) or
+/// the `RateLimit-Reset` header
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    header(headers, "retry-after")
+        .and_then(|s| s.parse().ok())
+        .map(Duration::from_secs)
+        .or_else(|| rate_limit_reset(headers))
+}
+
+/// Time until the rate limit is reset, from the `RateLimit-Reset` header (a UNIX timestamp)
+fn rate_limit_reset(headers: &HeaderMap) -> Option<Duration> {
+    let reset: u64 = header(headers, "ratelimit-reset")?.parse().ok()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(Duration::from_secs(reset.saturating_sub(now)))
+}
+
+/// Extracts the error message from the body of an error response. Gitlab usually answers with
+/// `{"message": ...}`, or `{"error": ..., "error_description": ...}` for OAuth-related errors
+fn error_message(body: &[u8]) -> String {
+    #[derive(Deserialize)]
+    struct ErrorBody {
+        message: Option<serde_json::Value>,
+        error: Option<String>,
+        error_description: Option<String>,
     }
+    match serde_json::from_slice::<ErrorBody>(body) {
+        Ok(ErrorBody {
+            message: Some(serde_json::Value::String(msg)),
+            ..
+        }) => msg,
+        Ok(ErrorBody {
+            message: Some(msg), ..
+        }) => msg.to_string(),
+        Ok(ErrorBody {
+            error: Some(error),
+            error_description,
+            ..
+        }) => match error_description {
+            Some(desc) => format!("{error}: {desc}"),
+            None => error,
+        },
+        _ => {
+            let body = String::from_utf8_lossy(body);
+            let body = body.trim();
+            if body.starts_with('<') {
+                "(HTML page)".into()
+            } else {
+                body.chars().take(200).collect()
+            }
+        }
+    }
+}
+
+/// A Gitlab user account
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GitlabUser {
+    pub id: u64,
+    pub username: String,
 }
 
+/// A todo as returned by the Gitlab API
 #[derive(Debug, Clone, Deserialize)]
-#[allow(dead_code)]
 pub struct GitlabTodo {
     pub id: usize,
     pub body: String,
@@ -77,9 +730,12 @@ pub struct GitlabTodo {
     pub author: Option<String>,
     #[serde(deserialize_with = "get_entity_path", default)]
     pub project: Option<String>,
-    #[serde(deserialize_with = "get_entity_path", default)]
+    #[serde(deserialize_with = "get_group_path", default)]
     pub group: Option<String>,
     pub target_url: Url,
+    /// Title of the merge request, issue... the todo is about, if it has one
+    #[serde(rename = "target", deserialize_with = "get_target_title", default)]
+    pub target_title: Option<String>,
 }
 
 macro_rules! get_struct_field {
@@ -111,30 +767,38 @@ macro_rules! get_struct_field {
 }
 
 get_struct_field!(get_entity_path(path_with_namespace) -> Option: String);
+get_struct_field!(get_group_path(full_path) -> Option: String);
 get_struct_field!(get_username(username) -> Option: String);
 
+/// Unlike projects and groups, the target may be null or have no title, e.g. for alerts
+fn get_target_title<'de, D>(de: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Target {
+        title: Option<String>,
+    }
+    Ok(Option::<Target>::deserialize(de)?.and_then(|t| t.title))
+}
+
 impl GitlabTodo {
-    pub fn into_todo(self, config: &AppConfig) -> Result<Todo, Error> {
-        use std::str::FromStr;
-        fn parse_date(raw: impl AsRef<str>) -> AppResult<Date> {
-            let raw = raw.as_ref();
-            raw.split_once('T')
-                .ok_or(Error::msg(format!("Couldn't parse date from '{raw}'")))
-                .and_then(|(d, _)| Date::from_str(d))
-        }
+    /// Converts this Gitlab todo to a todo.txt item, tagged according to the config
+    pub fn into_todo(self, config: &AppConfig) -> AppResult<Todo> {
         let done = self.is_done();
 
+        let text = body::render(&self, &config.body);
         let description = if config.no_escape_meta {
-            Cow::Borrowed(self.body.as_str())
+            Cow::Borrowed(text.as_str())
         } else {
-            Todo::escape_description(self.body.as_str())
+            Todo::escape_description(&text)
         };
         let mut result = Todo::new(
             done,
             None,
-            Some(parse_date(self.created_at)?),
+            Some(parse_date(&self.created_at)?),
             if done {
-                Some(parse_date(self.updated_at)?)
+                Some(parse_date(&self.updated_at)?)
             } else {
                 None
             },
@@ -160,4 +824,148 @@ impl GitlabTodo {
     pub fn is_done(&self) -> bool {
         self.state == STATE_DONE
     }
+
+    /// The day the todo was created, in UTC
+    pub fn created(&self) -> Result<Date, ParseError> {
+        parse_date(&self.created_at)
+    }
+}
+
+/// Parses the date of a timestamp from the API, such as `2024-01-01T12:00:00.000Z`
+fn parse_date(raw: &str) -> Result<Date, ParseError> {
+    raw.split_once('T')
+        .ok_or_else(|| ParseError::new(format!("Couldn't parse date from '{raw}'")))
+        .and_then(|(d, _)| d.parse())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_message() {
+        assert_eq!(
+            error_message(br#"{"message":"401 Unauthorized"}"#),
+            "401 Unauthorized"
+        );
+        assert_eq!(
+            error_message(br#"{"message":{"state":["is invalid"]}}"#),
+            r#"{"state":["is invalid"]}"#
+        );
+        assert_eq!(
+            error_message(br#"{"error":"insufficient_scope","error_description":"Needs api"}"#),
+            "insufficient_scope: Needs api"
+        );
+        assert_eq!(error_message(b"<!DOCTYPE html><html>"), "(HTML page)");
+        assert_eq!(error_message(b" Bad gateway\n"), "Bad gateway");
+    }
+
+    #[test]
+    fn test_retry_delays() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let reset = (now.as_secs() + 30).to_string();
+        headers.insert("ratelimit-reset", reset.parse().unwrap());
+        let delay = retry_after(&headers).unwrap();
+        assert!(delay > Duration::from_secs(28) && delay <= Duration::from_secs(30));
+        headers.insert("retry-after", "5".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(5)));
+
+        let api = GitlabAPI::new("https://git.example".parse().unwrap(), "token").unwrap();
+        for (attempt, max) in [(0, 1000), (1, 2000), (3, 8000), (10, 60000), (40, 60000)] {
+            let delay = api.backoff(attempt).as_millis();
+            assert!(
+                delay >= max / 2 && delay <= max,
+                "{delay}ms for attempt {attempt}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_response_cache() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_static("W/\"abc\""));
+        headers.insert("x-next-page", HeaderValue::from_static("2"));
+        headers.insert("x-total-pages", HeaderValue::from_static("3"));
+        headers.insert("x-request-id", HeaderValue::from_static("123"));
+        let response = ApiResponse {
+            url: "https://git.example/api/v4/todos".into(),
+            status: StatusCode::OK,
+            headers,
+            body: b"[]".to_vec(),
+        };
+        let cached = response.to_cache().unwrap();
+        assert_eq!(cached.etag, "W/\"abc\"");
+        assert_eq!(
+            cached.headers,
+            [
+                ("x-next-page".into(), "2".into()),
+                ("x-total-pages".into(), "3".into())
+            ]
+        );
+
+        let reused = ApiResponse::from_cache(response.url.clone(), cached);
+        assert_eq!(reused.status, StatusCode::OK);
+        assert_eq!(header(&reused.headers, "x-next-page"), Some("2"));
+        assert_eq!(header(&reused.headers, "x-total-pages"), Some("3"));
+        assert_eq!(reused.body, response.body);
+    }
+
+    #[test]
+    fn test_host_limiter() {
+        let a = GitlabAPI::new("https://limit.example".parse().unwrap(), "a").unwrap();
+        let b = GitlabAPI::new("https://limit.example/gitlab".parse().unwrap(), "b").unwrap();
+        let c = GitlabAPI::new("https://other.example".parse().unwrap(), "c").unwrap();
+        assert!(Arc::ptr_eq(&a.limiter, &b.limiter));
+        assert!(!Arc::ptr_eq(&a.limiter, &c.limiter));
+        assert_eq!(a.limiter.available_permits(), 4);
+    }
+
+    #[test]
+    fn test_api_base() {
+        for host in ["https://git.example", "https://example.com/gitlab"] {
+            let api = GitlabAPI::new(host.parse().unwrap(), "token").unwrap();
+            assert_eq!(api.base.as_str(), format!("{host}/api/v4/"));
+        }
+    }
+
+    #[test]
+    fn test_into_todo() {
+        let todo = |target_type: &str, target: serde_json::Value| -> GitlabTodo {
+            serde_json::from_value(serde_json::json!({
+                "id": 7,
+                "body": "Looks good\n\n> Can you review?\n\n/approve",
+                "state": "pending",
+                "created_at": "2024-01-01T10:00:00Z",
+                "updated_at": "2024-01-01T10:00:00Z",
+                "action_name": "mentioned",
+                "target_type": target_type,
+                "target": target,
+                "project": {"path_with_namespace": "main/app"},
+                "target_url": "https://git.example/main/app/-/merge_requests/1",
+            }))
+            .unwrap()
+        };
+        let mut config = AppConfig {
+            context_tag: None,
+            ..Default::default()
+        };
+        let line =
+            |todo: GitlabTodo, config: &AppConfig| todo.into_todo(config).unwrap().to_string();
+        let title = serde_json::json!({"title": "Fix the build: key:value"});
+        assert_eq!(
+            line(todo("MergeRequest", title.clone()), &config),
+            "2024-01-01 [MergeRequest:mentioned] Fix the build: key\\:value +main/app id:7"
+        );
+        assert_eq!(
+            line(todo("Commit", serde_json::Value::Null), &config),
+            "2024-01-01 [Commit:mentioned] Looks good +main/app id:7"
+        );
+        config.body.use_title = false;
+        assert_eq!(
+            line(todo("MergeRequest", title), &config),
+            "2024-01-01 [MergeRequest:mentioned] Looks good +main/app id:7"
+        );
+    }
 }
@@ -0,0 +1,200 @@
+use crate::error::{AbortReason, AppResult, IoError, ParseError};
+use documented::DocumentedFields;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Commands run around the phases of a sync, see [`AppConfig::hooks`]. Each one is a list of the
+/// program and its arguments, and can be null for none
+///
+/// [`AppConfig::hooks`]: crate::config::AppConfig::hooks
+#[derive(Deserialize, Clone, Debug, Default, DocumentedFields)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    /// Run before fetching the todos. The sync is aborted if it fails
+    #[serde(default)]
+    pub before_fetch: Option<Vec<String>>,
+    /// Run once the changes are planned, with the [`Plan`] as JSON on stdin. The sync is aborted
+    /// if it fails, and if it prints a plan the files get its lines instead
+    #[serde(default)]
+    pub after_merge: Option<Vec<String>>,
+    /// Run after writing the files, if any changed, with the applied [`Plan`] as JSON on stdin.
+    /// Its failure is only logged
+    #[serde(default)]
+    pub after_write: Option<Vec<String>>,
+}
+
+/// The changes a sync makes to the todo files
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Plan {
+    pub files: Vec<PlannedFile>,
+}
+
+/// The changes to a todo file. Only `path` and `lines` are read back from the `after_merge` hook
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PlannedFile {
+    pub path: PathBuf,
+    /// Numbers of synced todos created, updated and deleted
+    #[serde(default)]
+    pub new: usize,
+    #[serde(default)]
+    pub updated: usize,
+    #[serde(default)]
+    pub deleted: usize,
+    /// Lines not in the file yet
+    #[serde(default)]
+    pub added: Vec<String>,
+    /// Lines of the file that won't be in it anymore
+    #[serde(default)]
+    pub removed: Vec<String>,
+    /// Resulting content of the file
+    pub lines: Vec<String>,
+    /// Whether the file was written, for the `after_write` hook
+    #[serde(default)]
+    pub written: bool,
+}
+
+impl PlannedFile {
+    pub fn new(path: PathBuf, changes: (usize, usize, usize), old: &[u8], new: &[u8]) -> Self {
+        let (old, lines) = (lines(old), lines(new));
+        Self {
+            path,
+            new: changes.0,
+            updated: changes.1,
+            deleted: changes.2,
+            added: missing(&lines, &old),
+            removed: missing(&old, &lines),
+            lines,
+            written: false,
+        }
+    }
+
+    /// The content of the file with these lines
+    pub fn content(&self) -> String {
+        self.lines.iter().map(|l| format!("{l}\n")).collect()
+    }
+}
+
+/// The lines of the content of a file
+pub(crate) fn lines(content: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The lines of `lines` that aren't in `other`, compared as multisets as lines can be duplicated
+pub(crate) fn missing(lines: &[String], other: &[String]) -> Vec<String> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for line in other {
+        *counts.entry(line).or_default() += 1;
+    }
+    lines
+        .iter()
+        .filter(|l| match counts.get_mut(l.as_str()) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
+            }
+            _ => true,
+        })
+        .cloned()
+        .collect()
+}
+
+/// Runs a hook, with `plan` on stdin if set. Returns the plan it printed, if any, or an error if
+/// it failed
+pub async fn run(name: &str, command: &[String], plan: Option<&Plan>) -> AppResult<Option<Plan>> {
+    let Some((program, args)) = command.split_first() else {
+        return Ok(None);
+    };
+    info!("Running {name} hook");
+    let mut child = Command::new(program)
+        .args(args)
+        .env("GITLAB_TODOTXT_HOOK", name)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| IoError::new(program, e))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // The output is read while the plan is written, as a hook echoing its input would otherwise
+    // block once the pipes are full
+    let send = async move {
+        if let Some(plan) = plan {
+            let json = serde_json::to_vec(plan).expect("plans are serializable");
+            // The hook may not read its input
+            if let Err(e) = stdin.write_all(&json).await {
+                debug!("Couldn't send the plan to the {name} hook: {e}");
+            }
+        }
+    };
+    let ((), output) = tokio::join!(send, child.wait_with_output());
+    let output = output.map_err(|e| IoError::new(program, e))?;
+    if !output.status.success() {
+        return Err(AbortReason::Vetoed {
+            hook: name.to_string(),
+            status: output.status.to_string(),
+        }
+        .into());
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if stdout.trim().is_empty() {
+        return Ok(None);
+    }
+    serde_json::from_str(&stdout).map(Some).map_err(|e| {
+        ParseError::new(format!("Invalid plan printed by the {name} hook: {e}")).into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_planned_file() {
+        let old = b"Keep id:1\nDup\nDup\nOld id:2\n";
+        let new = b"Keep id:1\nDup\nNew id:3\n";
+        let file = PlannedFile::new("todo.txt".into(), (1, 0, 1), old, new);
+        assert_eq!(file.added, ["New id:3"]);
+        assert_eq!(file.removed, ["Dup", "Old id:2"]);
+        assert_eq!(file.content(), String::from_utf8_lossy(new));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run() {
+        let sh = |script: &str| vec!["sh".to_string(), "-c".into(), script.into()];
+        let plan = Plan {
+            files: vec![PlannedFile::new(
+                "todo.txt".into(),
+                (1, 0, 0),
+                b"",
+                b"New\n",
+            )],
+        };
+
+        let echoed = run("after_merge", &sh("cat"), Some(&plan)).await;
+        assert_eq!(echoed.unwrap(), Some(plan.clone()));
+        let ignored = run("after_merge", &sh("true"), Some(&plan)).await;
+        assert_eq!(ignored.unwrap(), None);
+        let vetoed = run("after_merge", &sh("exit 3"), Some(&plan)).await;
+        assert!(matches!(vetoed, Err(crate::Error::Aborted(_))));
+        let invalid = run("after_merge", &sh("echo nope"), Some(&plan)).await;
+        assert!(matches!(invalid, Err(crate::Error::Parse(_))));
+
+        // Larger than the pipe buffers
+        let lines = (0..20000).map(|i| format!("Todo {i}"));
+        let big = Plan {
+            files: vec![PlannedFile {
+                lines: lines.collect(),
+                ..plan.files[0].clone()
+            }],
+        };
+        let echoed = run("after_merge", &sh("cat"), Some(&big)).await;
+        assert_eq!(echoed.unwrap(), Some(big));
+    }
+}
@@ -0,0 +1,24 @@
+//! Syncs the todos of a Gitlab user to a file in the [todo.txt](http://todotxt.org/) format.
+//!
+//! [`sync::SyncEngine`] runs the sync described by a [`config::AppConfig`], fetching todos with
+//! [`gitlab::GitlabAPI`] and merging them into the file using the [`todo::Todo`] model.
+
+pub mod body;
+pub mod config;
+pub mod daemon;
+pub mod doctor;
+pub mod error;
+pub mod filter;
+pub mod gitlab;
+pub mod hooks;
+pub mod notifier;
+pub mod report;
+pub mod session;
+pub mod state;
+pub mod status;
+pub mod sync;
+pub mod todo;
+pub mod tui;
+pub mod webhook;
+
+pub use error::{AppResult, Error};
@@ -1,254 +1,237 @@
-use crate::gitlab::GitlabTodo;
-use crate::todo::Todo;
-use std::collections::HashMap;
-
-use config::{AppConfig, DonePolicy};
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::future::{BoxFuture, FutureExt};
+use gitlab_todotxt_sync::config::AppConfig;
+use gitlab_todotxt_sync::daemon::Daemon;
+use gitlab_todotxt_sync::doctor::Repair;
+use gitlab_todotxt_sync::error::ConfigError;
+use gitlab_todotxt_sync::report::SyncReport;
+use gitlab_todotxt_sync::session::Session;
+use gitlab_todotxt_sync::status::Status;
+use gitlab_todotxt_sync::sync::SyncEngine;
+use gitlab_todotxt_sync::todo::Date;
+use gitlab_todotxt_sync::tui;
+use gitlab_todotxt_sync::AppResult;
 use log::*;
-use std::error::Error as StdError;
-use std::io::SeekFrom;
-use std::path::Path;
-use tokio::fs::File;
-use tokio::io::{stdout, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
-
-pub(crate) use anyhow::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use tokio::io::{stdout, AsyncWriteExt};
+
+/// Syncs your Gitlab todos to a todo.txt file
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// Path to the config file (default = $CONFIG_DIR/gitlab-todotxt-sync/config.json)
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Apply the sync even if a safety check would abort it: too many deletions, or a token
+    /// belonging to another user than the previous run
+    #[arg(long)]
+    force: bool,
+    /// Fetch every done todo instead of only the ones needed since the last sync
+    #[arg(long)]
+    full: bool,
+    /// Save the requests to Gitlab and their responses to a directory, e.g. to attach them to a
+    /// bug report. The token isn't saved
+    #[arg(long, value_name = "DIR", conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// With --record, replace the texts of the responses (todo bodies, titles, names...)
+    #[arg(long, requires = "record")]
+    redact: bool,
+    /// Serve the responses saved with --record instead of contacting Gitlab
+    #[arg(long, value_name = "DIR")]
+    replay: Option<PathBuf>,
+    /// What to print after the sync
+    #[arg(long, value_enum, default_value_t = Format::Todo)]
+    format: Format,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-mod config;
-mod gitlab;
-mod todo;
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    /// The content of the todo files
+    Todo,
+    /// The changed todos of each file, and their number
+    Text,
+    /// The report of the text format as JSON
+    Json,
+}
 
-type AppResult<T> = Result<T, Error>;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Sync periodically and whenever a todo file changes, until stopped by SIGTERM or Ctrl-C
+    Daemon {
+        /// Seconds between two syncs
+        #[arg(long, default_value_t = 300)]
+        interval: u64,
+        /// Seconds to wait for a todo file to stop changing before syncing it
+        #[arg(long, default_value_t = 2)]
+        debounce: u64,
+    },
+    /// Check the todo files for duplicated ids, synced lines without an id or with an invalid
+    /// one, and lines of todos that aren't the user's
+    Doctor {
+        /// Repair the problems: duplicates are merged, and the other lines are either stripped of
+        /// the context tag (strip) or also moved to the end of the file with a quarantine context
+        /// (quarantine)
+        #[arg(long, value_name = "HOW")]
+        fix: Option<Repair>,
+    },
+    /// Triage the synced todos in an interactive terminal UI: complete (marking them as done on
+    /// Gitlab), snooze, prioritize and open them
+    Tui,
+    /// Hide a synced todo until a date, with a threshold date (t: tag), then sync
+    Snooze {
+        /// Gitlab id of the todo, as in its id: tag
+        id: usize,
+        /// A date (YYYY-MM-DD), or a period from today such as 3d, 2b (business days), 1w or 1m
+        until: String,
+    },
+    /// Summarize the pending todos by project, action and age, without syncing
+    Status {
+        /// Print a single line, for shell prompts and status bars
+        #[arg(long, conflicts_with = "json")]
+        line: bool,
+        /// Print the summary as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn StdError>> {
+async fn main() -> ExitCode {
     env_logger::init();
-    let config = dirs::config_dir()
-        .expect("Could not determine config dir")
-        .join("gitlab-todotxt-sync/config.json");
-    let config = AppConfig::read_from(&config).await?;
-
-    let gltodos = get_gitlab_todos(&config).await?;
-
-    let todos = gltodos
-        .into_iter()
-        .map(|gl| -> AppResult<(usize, Todo)> {
-            let id = gl.id;
-            gl.into_todo(&config).map(|t| (id, t))
-        })
-        .collect::<AppResult<HashMap<_, _>>>()?;
-
-    let mut tf = File::options()
-        .read(true)
-        .create(true)
-        .write(true)
-        .open(&config.todo_file)
-        .await?;
-
-    let existing = read_existing(&config, &mut tf).await?;
-    let splitf = |t: &Todo| {
-        config
-            .context_tag
-            .as_ref()
-            .map(|ctx| t.has_context(ctx))
-            .unwrap_or(true)
-    };
-    let (mut existing, other): (Vec<_>, _) = existing.into_iter().partition(splitf);
-    update_todos(
-        &mut existing,
-        todos,
-        config.done_todo_policy == DonePolicy::Add,
-    );
-    let todos = [other, existing].concat();
-
-    let mut buf: Vec<u8> = Vec::new();
-    Todo::write_file(&mut buf, todos.iter()).await?;
-
-    info!(
-        "Writing {} todos to file ({} bytes):",
-        todos.len(),
-        buf.len()
-    );
-    stdout().write_all(&buf).await?;
-
-    tf.set_len(0).await?; // Truncate file
-    tf.seek(SeekFrom::Start(0)).await?;
-    tf.write_all(&buf).await?;
-    tf.flush().await?;
-
-    Ok(())
-}
-
-async fn get_gitlab_todos(config: &AppConfig) -> Result<Vec<GitlabTodo>, Box<dyn StdError>> {
-    let api = config.get_api()?;
-
-    let gltodos: Vec<GitlabTodo> = if let Ok(json) = std::env::var("GITLAB_TODOS_JSON") {
-        info!("Loading from file {json}");
-        let mut todos: Vec<GitlabTodo> = from_file(json).await?;
-        if let DonePolicy::Ignore = config.done_todo_policy {
-            todos.retain(|t| !t.is_done());
+    let cli = Cli::parse();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{e}");
+            ExitCode::from(e.exit_code())
         }
-        todos
-    } else if let DonePolicy::Ignore = config.done_todo_policy {
-        api.get_pending_todos().await?
-    } else {
-        api.get_all_todos().await?
-    };
-    Ok(gltodos)
+    }
 }
 
-async fn read_existing(config: &AppConfig, tf: &mut File) -> Result<Vec<Todo>, Box<dyn StdError>> {
-    let existing = Todo::read_file(tf).await?;
-    info!(
-        "Read {} existing todos from {}",
-        existing.len(),
-        config.todo_file.to_str().unwrap()
-    );
-    Ok(existing)
-}
+async fn run(cli: Cli) -> AppResult<()> {
+    let config = match cli.config {
+        Some(path) => path,
+        None => dirs::config_dir()
+            .ok_or_else(|| ConfigError::Other("Could not determine config dir".into()))?
+            .join("gitlab-todotxt-sync/config.json"),
+    };
+    let config = AppConfig::read_from(&config).await?;
 
-fn update_todos(
-    existing: &mut Vec<Todo>,
-    mut todos: HashMap<usize, Todo>,
-    add_done: bool,
-) -> (usize, usize, usize) {
-    fn get_id(t: &Todo) -> Option<usize> {
-        match t
-            .get_data("id")
-            .ok_or("Todo is missing an id data tag".to_string())
-            .and_then(|id| {
-                id.parse::<usize>()
-                    .map_err(|_| format!("Couldn't parse id as usize: {id}"))
-            }) {
-            Ok(id) => Some(id),
-            Err(e) => {
-                warn!("{}", e);
-                None
-            }
+    let mut engine = SyncEngine::new(config)?
+        .with_force(cli.force)
+        .with_full_fetch(cli.full);
+    if let Some(dir) = cli.record {
+        engine = engine.with_session(Session::record(dir, cli.redact)?);
+    } else if let Some(dir) = cli.replay {
+        engine = engine.with_session(Session::replay(dir)?);
+    }
+    match cli.command {
+        Some(Command::Daemon { interval, debounce }) => {
+            // Before the first sync, which a signal must not interrupt
+            let shutdown = shutdown_signal();
+            return Daemon::new(engine, Duration::from_secs(interval))
+                .with_debounce(Duration::from_secs(debounce))
+                .run(shutdown)
+                .await;
+        }
+        Some(Command::Doctor { fix }) => return doctor(&engine, fix).await,
+        Some(Command::Snooze { id, until }) => {
+            let until = Date::today().parse_after(&until)?;
+            engine.snooze(id, until).await?;
+            let out = format!("Snoozed todo #{id} until {until}\n");
+            stdout().write_all(out.as_bytes()).await?;
+            return Ok(());
+        }
+        Some(Command::Tui) => return tui::run(&engine.with_push_completed(true)).await,
+        Some(Command::Status { line, json }) => {
+            let todos = engine.pending_todos().await?;
+            let status = Status::new(&todos, &Date::today(), &engine.config().body);
+            let out = match (line, json) {
+                (true, _) => format!("{}\n", status.line()),
+                (_, true) => format!("{}\n", status.to_json()),
+                _ => status.to_string(),
+            };
+            stdout().write_all(out.as_bytes()).await?;
+            return Ok(());
         }
+        None => {}
     }
-    let mut upd = 0;
-    let mut del = 0;
-    existing.retain_mut(|extd| {
-        if let Some(id) = get_id(extd) {
-            if let Some(td) = todos.remove(&id) {
-                if extd != &td {
-                    upd += 1;
-                    *extd = td;
+
+    let outcome = engine.run().await?;
+    let mut out = stdout();
+    match cli.format {
+        Format::Todo => {
+            for file in &outcome.files {
+                if outcome.files.len() > 1 {
+                    out.write_all(format!("==> {} <==\n", file.path.display()).as_bytes())
+                        .await?;
                 }
-            } else {
-                del += 1;
-                return false;
+                out.write_all(&file.content).await?;
             }
         }
-        true
-    });
-    let new = todos.len();
-    if add_done {
-        existing.extend(todos.into_values());
-    } else {
-        existing.extend(todos.into_values().filter(|t| !t.done));
+        Format::Text => {
+            let report = SyncReport::from(&outcome);
+            out.write_all(format!("{report}\n").as_bytes()).await?;
+        }
+        Format::Json => {
+            let report = SyncReport::from(&outcome);
+            out.write_all(format!("{}\n", report.to_json()).as_bytes())
+                .await?;
+        }
     }
-    (new, upd, del)
-}
-
-async fn from_file<T: serde::de::DeserializeOwned>(path: impl AsRef<Path>) -> AppResult<T> {
-    from_async_reader(File::open(path).await.map_err(Error::from)?).await
-}
 
-async fn from_async_reader<R, T>(rdr: R) -> AppResult<T>
-where
-    R: tokio::io::AsyncRead + Unpin,
-    T: serde::de::DeserializeOwned,
-{
-    let mut buf = vec![];
-    BufReader::new(rdr)
-        .read_to_end(&mut buf)
-        .await
-        .map_err(Error::from)?;
-    serde_json::from_slice(&buf).map_err(Error::from)
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::todo::{DescriptionPart, Todo};
-    use crate::update_todos;
-    use std::collections::{HashMap, HashSet};
-
-    #[test]
-    fn test_done_policy() {
-        let t1 = Todo::new(false, None, None, None, "Test 1 id:1 +test".to_string()).unwrap();
-        let t2 = Todo::new(false, None, None, None, "Test 2 id:2 +test".to_string()).unwrap();
-        let t3 = Todo::new(false, None, None, None, "Test 3 id:3 +test".to_string()).unwrap();
-        let mut t1d = t1.clone();
-        t1d.done = true;
-        let mut t2d = t2.clone();
-        t2d.done = true;
-        let mut t3d = t3.clone();
-        t3d.done = true;
-
-        fn test(mut existing: Vec<Todo>, todos: Vec<Todo>, result: &[Todo], add_done: bool) {
-            update_todos(&mut existing, map_of(todos), add_done);
-            let existing: HashSet<(&str, bool)> =
-                HashSet::from_iter(existing.iter().map(|t| (t.get_data("id").unwrap(), t.done)));
-            let result =
-                HashSet::from_iter(result.iter().map(|t| (t.get_data("id").unwrap(), t.done)));
-            assert_eq!(
-                existing, result,
-                "Testing update_todos with add_done = {:?}",
-                add_done
-            );
+/// Prints the problems of each todo file, repairing them with `fix`
+async fn doctor(engine: &SyncEngine, fix: Option<Repair>) -> AppResult<()> {
+    let mut out = String::new();
+    for diagnosis in engine.doctor(fix).await? {
+        let path = diagnosis.path.display();
+        for problem in &diagnosis.problems {
+            out += &format!("{path}: {problem}\n");
         }
-
-        test(
-            vec![],
-            vec![t1d.clone(), t2d.clone()],
-            &[t1d.clone(), t2d.clone()],
-            true,
-        );
-
-        test(
-            vec![t1.clone()],
-            vec![t1d.clone(), t2.clone(), t3d.clone()],
-            &[t1d.clone(), t2.clone()],
-            false,
-        );
+        out += &match (diagnosis.problems.len(), diagnosis.repaired) {
+            (0, _) => format!("{path}: no problems found\n"),
+            (n, true) => format!("{path}: repaired {n} problems\n"),
+            (n, false) => format!("{path}: {n} problems, run with --fix to repair them\n"),
+        };
     }
+    stdout().write_all(out.as_bytes()).await?;
+    Ok(())
+}
 
-    #[test]
-    fn test_tag_escape() {
-        const PRJ: &str = "testprj";
-        const CTX: &str = "testctx";
-        const DATAK: &str = "test";
-        const DATAV: &str = "data";
-        let todo = Todo::new(false, None, None, None, "Test".into()).unwrap()
-            + DescriptionPart::Project(PRJ)
-            + DescriptionPart::Context(CTX)
-            + DescriptionPart::Data(DATAK, DATAV);
-        let escaped = Todo::new(
-            false,
-            None,
-            None,
-            None,
-            Todo::escape_description(&todo.description).to_string(),
-        )
-        .unwrap();
-        assert_eq!(
-            escaped.find_meta().collect::<Vec<_>>(),
-            vec![],
-            "Escaped description shouldn't return any meta"
-        );
-        for s in [PRJ, CTX, DATAK, DATAV] {
-            assert!(
-                escaped.description.find(s).is_some(),
-                "Escaped description should still contain '{}'",
-                s
-            );
+/// Completes on SIGTERM or Ctrl-C.
+///
+/// On Unix, the signals are listened to as soon as this is called, rather than when the future
+/// is first polled: the daemon only polls it once its first sync is done, and until then the
+/// default action of the signals would kill it, possibly while it writes a todo file.
+fn shutdown_signal() -> BoxFuture<'static, ()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::interrupt()),
+        ) {
+            (Ok(mut term), Ok(mut interrupt)) => {
+                return async move {
+                    tokio::select! {
+                        _ = term.recv() => {}
+                        _ = interrupt.recv() => {}
+                    }
+                }
+                .boxed();
+            }
+            (Err(e), _) | (_, Err(e)) => warn!("Couldn't listen for SIGTERM and SIGINT: {e}"),
         }
     }
-
-    fn map_of(tds: impl IntoIterator<Item = Todo>) -> HashMap<usize, Todo> {
-        HashMap::from_iter(
-            tds.into_iter()
-                .map(|t| (t.get_data("id").unwrap().parse().unwrap(), t)),
-        )
+    async {
+        let _ = tokio::signal::ctrl_c().await;
     }
+    .boxed()
 }
@@ -0,0 +1,147 @@
+use crate::body::{self, BodyConfig};
+use crate::gitlab::GitlabTodo;
+use documented::DocumentedFields;
+use log::*;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use std::sync::LazyLock;
+
+const APP_NAME: &str = "gitlab-todotxt-sync";
+
+/// Settings of the notifications for new todos, see [`AppConfig::notifications`]
+///
+/// [`AppConfig::notifications`]: crate::config::AppConfig::notifications
+#[derive(Deserialize, Clone, Debug, DocumentedFields)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// Only notify todos created by these actions, e.g. ["review_requested", "assigned"]. All
+    /// todos are notified if empty
+    #[serde(default)]
+    pub actions: Vec<String>,
+    /// Template of the title, see [`render`] for the placeholders
+    #[serde(default = "NotifyConfig::default_title")]
+    pub title: String,
+    /// Template of the body
+    #[serde(default = "NotifyConfig::default_body")]
+    pub body: String,
+    /// Command to run for each new todo instead of showing a desktop notification, as a list of
+    /// the program and its arguments, which are templates too. Can be null for none
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+}
+
+impl NotifyConfig {
+    fn default_title() -> String {
+        "{author}: {action} on {project}".into()
+    }
+
+    fn default_body() -> String {
+        "{body}\n{url}".into()
+    }
+}
+
+/// Notifies each new todo allowed by the config, logging failures. Their text is the one of the
+/// todo file, see [`body::render`]
+pub async fn notify(config: &NotifyConfig, body_config: &BodyConfig, todos: &[GitlabTodo]) {
+    for todo in todos {
+        if !config.actions.is_empty() && !config.actions.contains(&todo.action_name) {
+            continue;
+        }
+        let render = |template: &str| render(template, todo, body_config);
+        let result = match &config.command {
+            Some(command) => run_command(command, render)
+                .await
+                .map_err(|e| e.to_string()),
+            None => show(render(&config.title), render(&config.body)).await,
+        };
+        if let Err(e) = result {
+            warn!("Couldn't notify todo #{}: {e}", todo.id);
+        }
+    }
+}
+
+/// Replaces the placeholders of a template with the fields of a todo: `{id}`, `{action}`,
+/// `{type}`, `{body}`, `{author}`, `{project}` (or group) and `{url}`. Other text between braces
+/// is kept, as are placeholders in the values
+pub fn render(template: &str, todo: &GitlabTodo, body_config: &BodyConfig) -> String {
+    static PLACEHOLDER_REG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{(\w+)\}").unwrap());
+    PLACEHOLDER_REG
+        .replace_all(template, |caps: &Captures| match &caps[1] {
+            "id" => todo.id.to_string(),
+            "action" => todo.action_name.replace('_', " "),
+            "type" => todo.target_type.clone(),
+            "body" => body::render(todo, body_config),
+            "author" => todo.author.clone().unwrap_or_default(),
+            "project" => todo
+                .project
+                .as_ref()
+                .or(todo.group.as_ref())
+                .cloned()
+                .unwrap_or_default(),
+            "url" => todo.target_url.to_string(),
+            _ => caps[0].to_string(),
+        })
+        .into_owned()
+}
+
+async fn run_command(command: &[String], render: impl Fn(&str) -> String) -> std::io::Result<()> {
+    let Some((program, args)) = command.split_first() else {
+        return Ok(());
+    };
+    let status = tokio::process::Command::new(render(program))
+        .args(args.iter().map(|arg| render(arg)))
+        .status()
+        .await?;
+    if !status.success() {
+        warn!("Notification command exited with {status}");
+    }
+    Ok(())
+}
+
+/// Shows a desktop notification, through D-Bus on Linux
+async fn show(title: String, body: String) -> Result<(), String> {
+    tokio::task::spawn_blocking(move || {
+        notify_rust::Notification::new()
+            .appname(APP_NAME)
+            .summary(&title)
+            .body(&body)
+            .show()
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let todo: GitlabTodo = serde_json::from_value(serde_json::json!({
+            "id": 7,
+            "body": "Add **notifications**\nto {url} for {author}",
+            "state": "pending",
+            "created_at": "2024-01-01T00:00:00.000Z",
+            "updated_at": "2024-01-01T00:00:00.000Z",
+            "action_name": "review_requested",
+            "target_type": "MergeRequest",
+            "author": {"username": "jdoe"},
+            "project": {"path_with_namespace": "main/app"},
+            "target_url": "https://git.example/main/app/-/merge_requests/3",
+        }))
+        .unwrap();
+        let config: NotifyConfig = serde_json::from_str("{}").unwrap();
+        let render = |template: &str| render(template, &todo, &BodyConfig::default());
+        assert_eq!(render(&config.title), "jdoe: review requested on main/app");
+        assert_eq!(
+            render(&config.body),
+            "Add notifications to {url} for {author}\nhttps://git.example/main/app/-/merge_requests/3"
+        );
+        assert_eq!(
+            render("#{id} {type} {unknown}"),
+            "#7 MergeRequest {unknown}"
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use crate::sync::{ChangeKind, Changes, SyncOutcome};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+/// What a sync changed, for people (through [`Display`]) and programs (as JSON), e.g. status bar
+/// widgets or CI dashboards
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SyncReport {
+    pub files: Vec<FileReport>,
+    pub totals: Totals,
+}
+
+/// The changes to one todo file
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FileReport {
+    pub path: PathBuf,
+    /// Whether the file was rewritten, which is skipped when its content didn't change
+    pub written: bool,
+    pub changes: Changes,
+}
+
+/// Number of changed todos of each kind, in every file
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Totals {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub pushed: usize,
+    pub conflicts: usize,
+}
+
+impl From<&SyncOutcome> for SyncReport {
+    fn from(outcome: &SyncOutcome) -> Self {
+        let files: Vec<FileReport> = outcome
+            .files
+            .iter()
+            .map(|f| FileReport {
+                path: f.path.clone(),
+                written: f.written,
+                changes: f.changes.clone(),
+            })
+            .collect();
+        let total = |kind| files.iter().map(|f| f.changes.count(kind)).sum();
+        let totals = Totals {
+            added: total(ChangeKind::Added),
+            updated: total(ChangeKind::Updated),
+            removed: total(ChangeKind::Removed),
+            pushed: total(ChangeKind::Pushed),
+            conflicts: total(ChangeKind::Conflict),
+        };
+        Self { files, totals }
+    }
+}
+
+impl SyncReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("reports are serializable")
+    }
+}
+
+impl Display for SyncReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for file in &self.files {
+            let unchanged = if file.written { "" } else { " (unchanged)" };
+            writeln!(f, "{}{unchanged}", file.path.display())?;
+            for change in &file.changes.0 {
+                let kind = match change.kind {
+                    ChangeKind::Added => "added",
+                    ChangeKind::Updated => "updated",
+                    ChangeKind::Removed => "removed",
+                    ChangeKind::Pushed => "pushed",
+                    ChangeKind::Conflict => "conflict",
+                };
+                let line = change.after.as_ref().or(change.before.as_ref());
+                writeln!(f, "  {kind} #{}: {}", change.id, line.map_or("", |l| l))?;
+            }
+        }
+        let Totals {
+            added,
+            updated,
+            removed,
+            pushed,
+            conflicts,
+        } = &self.totals;
+        write!(
+            f,
+            "{added} added, {updated} updated, {removed} removed, {pushed} pushed, {conflicts} conflicts"
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::{FileOutcome, TodoChange};
+    use serde_json::json;
+
+    #[test]
+    fn test_report() {
+        let change = |kind, id, before: Option<&str>, after: Option<&str>| TodoChange {
+            kind,
+            id,
+            before: before.map(Into::into),
+            after: after.map(Into::into),
+        };
+        let outcome = SyncOutcome {
+            files: vec![
+                FileOutcome {
+                    path: "todo.txt".into(),
+                    changes: Changes(vec![
+                        change(
+                            ChangeKind::Conflict,
+                            1,
+                            Some("x Fix id:1"),
+                            Some("Fix id:1"),
+                        ),
+                        change(ChangeKind::Removed, 2, Some("Old id:2"), None),
+                        change(ChangeKind::Added, 3, None, Some("New id:3")),
+                    ]),
+                    content: Vec::new(),
+                    written: true,
+                },
+                FileOutcome {
+                    path: "infra.txt".into(),
+                    changes: Changes::default(),
+                    content: Vec::new(),
+                    written: false,
+                },
+            ],
+            pushed: 0,
+            new_todos: Vec::new(),
+            fetched: Vec::new(),
+        };
+        let report = SyncReport::from(&outcome);
+        assert_eq!(
+            report.to_string(),
+            "todo.txt\n  conflict #1: Fix id:1\n  removed #2: Old id:2\n  added #3: New id:3\n\
+            infra.txt (unchanged)\n\
+            1 added, 0 updated, 1 removed, 0 pushed, 1 conflicts"
+        );
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(
+            json["files"][0]["changes"][0],
+            json!({"kind": "conflict", "id": 1, "before": "x Fix id:1", "after": "Fix id:1"})
+        );
+        assert_eq!(json["files"][0]["changes"][1].get("after"), None);
+        assert_eq!(json["totals"]["conflicts"], 1);
+    }
+}
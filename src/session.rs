@@ -0,0 +1,239 @@
+use crate::error::{AppResult, IoError, ParseError};
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Headers of the responses that are recorded, the others not being used by the client
+const RECORDED_HEADERS: [&str; 5] = ["etag", "x-next-page", "x-page", "x-total", "x-total-pages"];
+/// Fields of the response bodies replaced by [`REDACTED`] when recording with `redact`
+const REDACTED_FIELDS: [&str; 9] = [
+    "body",
+    "title",
+    "description",
+    "note",
+    "name",
+    "email",
+    "public_email",
+    "bio",
+    "avatar_url",
+];
+const REDACTED: &str = "[redacted]";
+
+/// Records the exchanges of a [`GitlabAPI`] with the server in a directory, or replays them
+/// instead of contacting the server, e.g. to reproduce a bug without access to the account.
+///
+/// Each exchange is stored as a JSON file, numbered in the order of the requests. The token is
+/// never recorded, as only the method and path of the requests are.
+///
+/// [`GitlabAPI`]: crate::gitlab::GitlabAPI
+#[derive(Debug)]
+pub enum Session {
+    Record {
+        dir: PathBuf,
+        redact: bool,
+        count: AtomicUsize,
+    },
+    Replay {
+        /// Recorded exchanges by method and path, in the order they were recorded
+        exchanges: Mutex<HashMap<(String, String), VecDeque<Exchange>>>,
+    },
+}
+
+/// A request and its response
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Exchange {
+    pub method: String,
+    /// Path and query of the URL, without the host which may differ when replaying
+    pub path: String,
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    /// The body if it is JSON, which the API always returns except for some errors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl Session {
+    /// Records the exchanges to `dir`, which is created if needed. With `redact`, the text
+    /// fields of the bodies such as todo bodies, titles and names are replaced, but not the
+    /// paths and usernames
+    pub fn record(dir: impl Into<PathBuf>, redact: bool) -> AppResult<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).map_err(|e| IoError::new(&dir, e))?;
+        Ok(Session::Record {
+            dir,
+            redact,
+            count: AtomicUsize::new(0),
+        })
+    }
+
+    /// Replays the exchanges recorded in `dir`
+    pub fn replay(dir: impl AsRef<Path>) -> AppResult<Self> {
+        let dir = dir.as_ref();
+        let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+            .and_then(|entries| entries.map(|e| Ok(e?.path())).collect())
+            .map_err(|e| IoError::new(dir, e))?;
+        files.retain(|f| f.extension().is_some_and(|ext| ext == "json"));
+        files.sort();
+        let mut exchanges: HashMap<_, VecDeque<Exchange>> = HashMap::new();
+        for file in files {
+            let data = std::fs::read(&file).map_err(|e| IoError::new(&file, e))?;
+            let exchange: Exchange = serde_json::from_slice(&data)
+                .map_err(|e| ParseError::new(format!("{}: {e}", file.display())))?;
+            let key = (exchange.method.clone(), exchange.path.clone());
+            exchanges.entry(key).or_default().push_back(exchange);
+        }
+        info!(
+            "Replaying {} requests from {}",
+            exchanges.values().map(VecDeque::len).sum::<usize>(),
+            dir.display()
+        );
+        Ok(Session::Replay {
+            exchanges: Mutex::new(exchanges),
+        })
+    }
+
+    /// The recorded response to a request, if replaying. The last response to a request is
+    /// reused once the others have been replayed, so that a session can be replayed by several
+    /// syncs
+    pub fn replayed(&self, method: &str, path: &str) -> Option<Option<Exchange>> {
+        let Session::Replay { exchanges } = self else {
+            return None;
+        };
+        let mut exchanges = exchanges.lock().unwrap();
+        let queue = exchanges.get_mut(&(method.to_string(), path.to_string()));
+        Some(queue.and_then(|queue| match queue.len() {
+            0 => None,
+            1 => queue.front().cloned(),
+            _ => queue.pop_front(),
+        }))
+    }
+
+    /// Stores an exchange, if recording. Failures are only logged, as they don't affect the sync
+    pub async fn record_exchange(&self, mut exchange: Exchange) {
+        let Session::Record { dir, redact, count } = self else {
+            return;
+        };
+        exchange
+            .headers
+            .retain(|(name, _)| RECORDED_HEADERS.contains(&name.as_str()));
+        if *redact {
+            if let Some(json) = &mut exchange.json {
+                redact_fields(json);
+            }
+        }
+        let n = count.fetch_add(1, Ordering::Relaxed) + 1;
+        let name: String = exchange
+            .path
+            .split('?')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let file = dir.join(format!("{n:04}-{}{name}.json", exchange.method));
+        let data = serde_json::to_vec_pretty(&exchange).expect("exchanges are serializable");
+        if let Err(e) = tokio::fs::write(&file, data).await {
+            warn!("Couldn't record to {}: {e}", file.display());
+        }
+    }
+}
+
+impl Exchange {
+    /// Builds an exchange from a response body, which is kept as JSON if possible
+    pub fn new(
+        method: &str,
+        path: &str,
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: &[u8],
+    ) -> Self {
+        let json = serde_json::from_slice(body).ok();
+        let text = match json {
+            Some(_) => None,
+            None => Some(String::from_utf8_lossy(body).into_owned()),
+        };
+        Self {
+            method: method.into(),
+            path: path.into(),
+            status,
+            headers,
+            json,
+            text,
+        }
+    }
+
+    pub fn body(&self) -> Vec<u8> {
+        match (&self.json, &self.text) {
+            (Some(json), _) => serde_json::to_vec(json).expect("JSON values are serializable"),
+            (None, Some(text)) => text.clone().into_bytes(),
+            (None, None) => Vec::new(),
+        }
+    }
+}
+
+fn redact_fields(value: &mut Value) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(redact_fields),
+        Value::Object(fields) => {
+            for (name, value) in fields {
+                match value {
+                    Value::String(s) if REDACTED_FIELDS.contains(&name.as_str()) => {
+                        *s = REDACTED.into()
+                    }
+                    value => redact_fields(value),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_record_replay() {
+        let dir =
+            std::env::temp_dir().join(format!("gitlab-todotxt-session-{}", std::process::id()));
+        let recorder = Session::record(&dir, true).unwrap();
+        let body = json!([{"id": 1, "body": "Secret plans", "author": {"username": "jdoe", "name": "J. Doe"}}]);
+        let headers = vec![
+            ("x-total".to_string(), "1".to_string()),
+            ("set-cookie".to_string(), "session=abc".to_string()),
+        ];
+        let todos = "/api/v4/todos?state=pending&page=1";
+        for status in [503, 200] {
+            let body = body.to_string();
+            let exchange = Exchange::new("GET", todos, status, headers.clone(), body.as_bytes());
+            recorder.record_exchange(exchange).await;
+        }
+        recorder
+            .record_exchange(Exchange::new("GET", "/api/v4/user", 401, vec![], b"<html>"))
+            .await;
+        assert_eq!(recorder.replayed("GET", todos), None);
+
+        let replayer = Session::replay(&dir).unwrap();
+        let replayed = |path| replayer.replayed("GET", path).unwrap();
+        assert_eq!(replayed(todos).unwrap().status, 503);
+        let ok = replayed(todos).unwrap();
+        assert_eq!(ok.status, 200);
+        assert_eq!(ok.headers, [("x-total".to_string(), "1".to_string())]);
+        assert_eq!(
+            ok.json.unwrap(),
+            json!([{"id": 1, "body": REDACTED, "author": {"username": "jdoe", "name": REDACTED}}])
+        );
+        assert_eq!(replayed(todos).unwrap().status, 200);
+        assert_eq!(replayed("/api/v4/user").unwrap().body(), b"<html>");
+        assert_eq!(replayed("/api/v4/users"), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
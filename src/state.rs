@@ -0,0 +1,76 @@
+use crate::error::{AppResult, Error, ParseError};
+use crate::gitlab::{GitlabUser, ResponseCache};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Data kept between two runs of the sync, stored as JSON in [`AppConfig::state_file`]
+///
+/// [`AppConfig::state_file`]: crate::config::AppConfig::state_file
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SyncState {
+    /// Gitlab user the todo file was last synced for
+    #[serde(default)]
+    pub user: Option<GitlabUser>,
+    /// Highest id of the todos fetched so far: newer todos have a higher id
+    #[serde(default)]
+    pub max_todo_id: Option<usize>,
+    /// Last responses of the Gitlab API, to only download them again if they changed
+    #[serde(default)]
+    pub response_cache: ResponseCache,
+}
+
+impl SyncState {
+    /// Reads the state from a file, returning an empty state if the file doesn't exist
+    pub async fn load(path: impl AsRef<Path>) -> AppResult<Self> {
+        let path = path.as_ref();
+        match tokio::fs::read(path).await {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| Error::from(ParseError::from(e)).with_path(path)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(Error::from(e).with_path(path)),
+        }
+    }
+
+    /// Writes the state to a file, replacing it atomically
+    pub async fn save(&self, path: impl AsRef<Path>) -> AppResult<()> {
+        let path = path.as_ref();
+        let data = serde_json::to_vec_pretty(self).expect("The state is serializable");
+        let tmp = path.with_extension("json.tmp");
+        async {
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::write(&tmp, data).await?;
+            tokio::fs::rename(&tmp, path).await
+        }
+        .await
+        .map_err(|e| Error::from(e).with_path(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_state_roundtrip() {
+        let path = std::env::temp_dir()
+            .join(format!("gitlab-todotxt-sync-{}", std::process::id()))
+            .join("state.json");
+        assert_eq!(SyncState::load(&path).await.unwrap(), SyncState::default());
+        let state = SyncState {
+            user: Some(GitlabUser {
+                id: 42,
+                username: "jdoe".into(),
+            }),
+            max_todo_id: Some(1234),
+            ..Default::default()
+        };
+        state.save(&path).await.unwrap();
+        assert_eq!(SyncState::load(&path).await.unwrap(), state);
+        tokio::fs::remove_dir_all(path.parent().unwrap())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+use crate::body::{self, BodyConfig};
+use crate::gitlab::GitlabTodo;
+use crate::todo::Date;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use url::Url;
+
+/// Age in days from which a todo counts as old
+const OLD_AFTER_DAYS: i64 = 7;
+/// Action of the todos asking for a review
+const REVIEW_REQUESTED: &str = "review_requested";
+/// Key of the todos without a project nor a group in [`Status::by_project`]
+const NO_PROJECT: &str = "(none)";
+
+/// Summary of the pending todos of the user, see [`SyncEngine::pending_todos`]
+///
+/// [`SyncEngine::pending_todos`]: crate::sync::SyncEngine::pending_todos
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Status {
+    pub pending: usize,
+    /// Number of pending todos by project path, or group path for group todos
+    pub by_project: BTreeMap<String, usize>,
+    /// Number of pending todos by action, e.g. "review_requested"
+    pub by_action: BTreeMap<String, usize>,
+    /// Number of pending todos created more than a week ago
+    pub older_than_week: usize,
+    pub oldest: Option<OldTodo>,
+    pub oldest_review: Option<OldTodo>,
+}
+
+/// A pending todo and its age
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct OldTodo {
+    pub id: usize,
+    /// Days since its creation
+    pub days: i64,
+    pub body: String,
+    pub url: Url,
+}
+
+impl Status {
+    /// Summarizes the pending todos among `todos`, with their age as of `today` and their text as
+    /// in the todo file. Todos whose creation date can't be parsed are counted, but not in the ages
+    pub fn new(todos: &[GitlabTodo], today: &Date, body_config: &BodyConfig) -> Self {
+        let mut status = Status::default();
+        for todo in todos.iter().filter(|t| !t.is_done()) {
+            status.pending += 1;
+            let project = todo.project.as_ref().or(todo.group.as_ref());
+            let project = project.map_or(NO_PROJECT, String::as_str);
+            *status.by_project.entry(project.into()).or_default() += 1;
+            *status
+                .by_action
+                .entry(todo.action_name.clone())
+                .or_default() += 1;
+
+            let Ok(created) = todo.created() else {
+                continue;
+            };
+            let days = today.days() - created.days();
+            if days > OLD_AFTER_DAYS {
+                status.older_than_week += 1;
+            }
+            let old = || OldTodo {
+                id: todo.id,
+                days,
+                body: body::render(todo, body_config),
+                url: todo.target_url.clone(),
+            };
+            if status.oldest.as_ref().is_none_or(|o| days > o.days) {
+                status.oldest = Some(old());
+            }
+            let oldest_review = status.oldest_review.as_ref();
+            if todo.action_name == REVIEW_REQUESTED && oldest_review.is_none_or(|o| days > o.days) {
+                status.oldest_review = Some(old());
+            }
+        }
+        status
+    }
+
+    /// Number of pending review requests
+    pub fn reviews(&self) -> usize {
+        self.by_action.get(REVIEW_REQUESTED).copied().unwrap_or(0)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("statuses are serializable")
+    }
+
+    /// A short summary on one line, for shell prompts and status bars, such as "5 todos, 2
+    /// reviews (oldest 9d), 1 older than a week"
+    pub fn line(&self) -> String {
+        let mut line = format!("{} todos", self.pending);
+        if let Some(oldest) = &self.oldest_review {
+            line += &format!(", {} reviews (oldest {}d)", self.reviews(), oldest.days);
+        } else if self.reviews() > 0 {
+            line += &format!(", {} reviews", self.reviews());
+        }
+        if self.older_than_week > 0 {
+            line += &format!(", {} older than a week", self.older_than_week);
+        }
+        line
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} pending todos, {} older than a week",
+            self.pending, self.older_than_week
+        )?;
+        for (name, oldest) in [("todo", &self.oldest), ("review", &self.oldest_review)] {
+            if let Some(o) = oldest {
+                writeln!(f, "Oldest {name}: {}d, {} ({})", o.days, o.body, o.url)?;
+            }
+        }
+        for (name, counts) in [("action", &self.by_action), ("project", &self.by_project)] {
+            let mut counts: Vec<_> = counts.iter().collect();
+            counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            let counts: Vec<String> = counts.iter().map(|(k, n)| format!("{k} {n}")).collect();
+            if !counts.is_empty() {
+                writeln!(f, "By {name}: {}", counts.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn todo(id: usize, action: &str, project: Option<&str>, created: &str) -> GitlabTodo {
+        let mut todo = json!({
+            "id": id,
+            "body": format!("**Todo**\n{id}"),
+            "state": "pending",
+            "created_at": created,
+            "updated_at": created,
+            "action_name": action,
+            "target_type": "MergeRequest",
+            "target_url": format!("https://git.example/{id}"),
+        });
+        if let Some(project) = project {
+            todo["project"] = json!({ "path_with_namespace": project });
+        }
+        serde_json::from_value(todo).unwrap()
+    }
+
+    #[test]
+    fn test_status() {
+        let mut done = todo(5, "assigned", Some("main/app"), "2023-01-01T00:00:00Z");
+        done.state = "done".into();
+        let todos = [
+            todo(
+                1,
+                "review_requested",
+                Some("main/app"),
+                "2024-01-01T10:00:00Z",
+            ),
+            todo(
+                2,
+                "review_requested",
+                Some("infra/db"),
+                "2024-01-09T10:00:00Z",
+            ),
+            todo(3, "assigned", Some("main/app"), "2023-12-20T10:00:00Z"),
+            todo(4, "mentioned", None, "invalid"),
+            done,
+        ];
+        let body = BodyConfig::default();
+        let status = Status::new(&todos, &"2024-01-10".parse().unwrap(), &body);
+        assert_eq!(status.pending, 4);
+        assert_eq!(status.older_than_week, 2);
+        assert_eq!(
+            status.oldest.as_ref().map(|o| (o.id, o.days)),
+            Some((3, 21))
+        );
+        assert_eq!(status.oldest_review.as_ref().map(|o| o.id), Some(1));
+        assert_eq!(
+            status.by_project,
+            BTreeMap::from([
+                ("(none)".into(), 1),
+                ("infra/db".into(), 1),
+                ("main/app".into(), 2)
+            ])
+        );
+        assert_eq!(
+            status.line(),
+            "4 todos, 2 reviews (oldest 9d), 2 older than a week"
+        );
+        assert_eq!(
+            status.to_string(),
+            "4 pending todos, 2 older than a week\n\
+            Oldest todo: 21d, Todo 3 (https://git.example/3)\n\
+            Oldest review: 9d, Todo 1 (https://git.example/1)\n\
+            By action: review_requested 2, assigned 1, mentioned 1\n\
+            By project: main/app 2, (none) 1, infra/db 1\n"
+        );
+        assert_eq!(Status::new(&[], &Date::today(), &body).line(), "0 todos");
+    }
+}
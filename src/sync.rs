@@ -0,0 +1,1391 @@
+use crate::config::{AppConfig, DonePolicy};
+use crate::doctor::{self, Problem, Repair};
+use crate::error::{AbortReason, AppResult, ConfigError, Error, ParseError};
+use crate::filter::Glob;
+use crate::gitlab::{GitlabAPI, GitlabTodo, GitlabUser, TodoQuery};
+use crate::hooks::{self, Plan, PlannedFile};
+use crate::notifier;
+use crate::session::Session;
+use crate::state::SyncState;
+use crate::todo::{Date, DescriptionPart, Todo, EXTENSION_TAGS, PRIORITY_TAG, THRESHOLD_TAG};
+use futures::future::try_join_all;
+use log::*;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Syncs Gitlab todos into the todo.txt files described by an [`AppConfig`]: the todo file, and
+/// those of its routes.
+///
+/// [`SyncEngine::run`] goes through every stage of the sync, which are also exposed
+/// individually: read existing → fetch → convert → [`update_todos`] → render → write. The todos
+/// are fetched once, then each file is updated with the ones routed to it, and only written
+/// once all of them passed the safety checks. The [hooks](crate::hooks::Hooks) of the config
+/// run before fetching, once the changes are planned, and after writing.
+///
+/// After the first run, done todos are fetched incrementally: only the ones the file may need
+/// are requested, see [`SyncEngine::done_since`].
+pub struct SyncEngine {
+    config: AppConfig,
+    api: GitlabAPI,
+    force: bool,
+    full: bool,
+    /// Whether a recorded [`Session`] is replayed, in which case the state isn't used
+    replay: bool,
+}
+
+/// Minimum number of synced todos for [`AppConfig::max_deletion_percent`] to apply
+const MIN_TODOS_FOR_DELETION_PERCENT: usize = 10;
+
+/// Result of a successful [`SyncEngine::run`]
+#[derive(Debug, Clone)]
+pub struct SyncOutcome {
+    /// One per todo file, starting with [`AppConfig::todo_file`]
+    pub files: Vec<FileOutcome>,
+    /// Number of todos completed in a file and marked as done on Gitlab, which are also listed
+    /// in the changes of their file
+    pub pushed: usize,
+    /// Pending todos created on Gitlab since the last sync, and added to a file by this one
+    pub new_todos: Vec<GitlabTodo>,
+    /// Todos fetched from Gitlab and matching the filters, only the ones in the scope for a
+    /// [`SyncEngine::refresh`]
+    pub fetched: Vec<GitlabTodo>,
+}
+
+/// Result of the sync of one todo file
+#[derive(Debug, Clone)]
+pub struct FileOutcome {
+    pub path: PathBuf,
+    /// The changes to the synced todos, see [`update_todos`]
+    pub changes: Changes,
+    /// Content of the todo file after the sync
+    pub content: Vec<u8>,
+    /// Whether the todo file was rewritten, which is skipped when its content didn't change
+    pub written: bool,
+}
+
+/// The changes to the synced todos of a file, as returned by [`update_todos`]
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Changes(pub Vec<TodoChange>);
+
+/// A change to a synced todo
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TodoChange {
+    pub kind: ChangeKind,
+    /// Gitlab id of the todo
+    pub id: usize,
+    /// The line before the sync, unless the todo was added
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+    /// The line after the sync, unless the todo was removed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Updated,
+    Removed,
+    /// Completed in the file and marked as done on Gitlab
+    Pushed,
+    /// Completed in the file but still pending on Gitlab, so reopened by the sync
+    Conflict,
+}
+
+/// Result of the [`SyncEngine::doctor`] of one todo file
+#[derive(Debug, Clone)]
+pub struct Diagnosis {
+    pub path: PathBuf,
+    pub problems: Vec<Problem>,
+    /// Whether the problems were repaired and the file rewritten
+    pub repaired: bool,
+}
+
+/// The todos of a project with a given target type, which a [`SyncEngine::refresh`] is limited to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Scope {
+    pub project_id: u64,
+    /// Full path of the project, as in the project tag of its todos
+    pub project_path: String,
+    /// Type of the todo targets, e.g. "MergeRequest"
+    pub target_type: String,
+}
+
+/// New content of a todo file, and the changes from [`update_todos`]
+type Update = (Vec<u8>, Changes);
+
+/// A todo file being synced
+struct TodoFile {
+    path: PathBuf,
+    file: File,
+    original: Vec<u8>,
+    existing: Vec<Todo>,
+}
+
+impl SyncEngine {
+    pub fn new(config: AppConfig) -> AppResult<Self> {
+        let api = config.get_api()?;
+        Ok(Self {
+            config,
+            api,
+            force: false,
+            full: false,
+            replay: false,
+        })
+    }
+
+    /// Skips the safety checks that abort the sync when it looks wrong: too many deletions, or
+    /// a Gitlab user that isn't the expected one
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Fetches every done todo instead of only the ones needed since the last sync, which also
+    /// removes the synced done todos that were deleted on Gitlab
+    pub fn with_full_fetch(mut self, full: bool) -> Self {
+        self.full = full;
+        self
+    }
+
+    /// Marks the todos completed in the files as done on Gitlab, whatever
+    /// [`AppConfig::push_completed`] says
+    pub fn with_push_completed(mut self, push: bool) -> Self {
+        self.config.push_completed |= push;
+        self
+    }
+
+    /// Records the exchanges with Gitlab, or replays recorded ones, see [`Session`]
+    pub fn with_session(mut self, session: Session) -> Self {
+        self.replay = matches!(session, Session::Replay { .. });
+        self.api = self.api.with_session(session);
+        self
+    }
+
+    pub fn config(&self) -> &AppConfig {
+        &self.config
+    }
+
+    pub async fn run(&self) -> AppResult<SyncOutcome> {
+        self.run_with(None).await
+    }
+
+    /// Syncs only the todos in `scope`, e.g. after a webhook reported a change to them: only
+    /// those are fetched, and the other todos of the files are left untouched
+    pub async fn refresh(&self, scope: &Scope) -> AppResult<SyncOutcome> {
+        info!(
+            "Refreshing {} todos of {}",
+            scope.target_type, scope.project_path
+        );
+        self.run_with(Some(scope)).await
+    }
+
+    async fn run_with(&self, scope: Option<&Scope>) -> AppResult<SyncOutcome> {
+        let mut state = self.load_state().await?;
+        let mut cache = std::mem::take(&mut state.response_cache);
+        self.api.set_response_cache(match scope {
+            Some(_) => cache.clone(),
+            None => std::mem::take(&mut cache),
+        });
+        let user = self.check_user(&state).await?;
+
+        let mut files = Vec::new();
+        for path in self.config.todo_files() {
+            files.push(self.open(path).await?);
+        }
+
+        let existing = files.iter().flat_map(|f| &f.existing);
+        let done_since = self.done_since(existing, &state, &user);
+        if let Some(hook) = &self.config.hooks.before_fetch {
+            hooks::run("before_fetch", hook, None).await?;
+        }
+        let mut gltodos = self.fetch(done_since, scope).await?;
+        let max_id = gltodos.iter().map(|t| t.id).max();
+        let pushed = if self.config.push_completed {
+            let existing = files.iter().flat_map(|f| &f.existing);
+            self.push_completed(existing, &mut gltodos).await?
+        } else {
+            HashSet::new()
+        };
+
+        let known_ids: HashSet<usize> = files
+            .iter()
+            .flat_map(|f| &f.existing)
+            .filter_map(|t| t.get_data("id")?.parse().ok())
+            .collect();
+        let mut new_todos: Vec<GitlabTodo> = gltodos
+            .iter()
+            .filter(|t| !t.is_done() && !known_ids.contains(&t.id))
+            .filter(|t| state.max_todo_id.is_some_and(|max| t.id > max))
+            .cloned()
+            .collect();
+
+        // Todos moving to another file keep the data the user added to them
+        let lines: HashMap<usize, Todo> = files
+            .iter()
+            .flat_map(|f| &f.existing)
+            .filter(|t| self.is_synced(t))
+            .filter_map(|t| Some((t.get_data("id")?.parse().ok()?, t.clone())))
+            .collect();
+        let today = Date::today();
+        let snoozed: HashSet<usize> = lines
+            .iter()
+            .filter(|(_, t)| t.threshold().ok().flatten().is_some_and(|d| d > today))
+            .map(|(id, _)| *id)
+            .collect();
+
+        let fetched = gltodos.clone();
+        let mut updates = Vec::new();
+        let mut removals = Vec::new();
+        let mut placed = HashSet::new();
+        for file in &mut files {
+            let (routed, rest) = gltodos
+                .into_iter()
+                .partition(|t| self.todo_file_for(t, &snoozed) == file.path);
+            gltodos = rest;
+            let mut todos = self.convert(routed)?;
+            let here: HashSet<usize> = file
+                .existing
+                .iter()
+                .filter_map(|t| t.get_data("id")?.parse().ok())
+                .collect();
+            for (id, todo) in todos.iter_mut().filter(|(id, _)| !here.contains(id)) {
+                if let Some(line) = lines.get(id) {
+                    keep_user_data(todo, line);
+                }
+            }
+            let existing = self.check(&file.path, std::mem::take(&mut file.existing));
+            let synced = existing
+                .iter()
+                .filter(|t| self.is_synced(t) && scope.is_none_or(|s| s.contains(t)))
+                .count();
+            let pushed_here = pushed_changes(&existing, &pushed);
+            let (todos, mut changes) = self.merge(existing, todos, done_since.is_some(), scope);
+            changes.0.extend(pushed_here);
+            let removed = changes.0.iter().filter(|c| c.kind == ChangeKind::Removed);
+            removals.push((removed.map(|c| c.id).collect::<Vec<_>>(), synced));
+            placed.extend(self.synced_ids(&todos));
+            updates.push((self.render(&todos).await?, changes));
+        }
+        // Todos moving to another file, e.g. when snoozed, aren't deleted
+        if !self.force {
+            for (file, (removed, synced)) in files.iter().zip(removals) {
+                let deleted = removed.iter().filter(|id| !placed.contains(id)).count();
+                check_deletions(&self.config, deleted, synced)
+                    .inspect_err(|_| warn!("Too many deletions in {}", file.path.display()))?;
+            }
+        }
+        if let Some(hook) = &self.config.hooks.after_merge {
+            self.run_after_merge(hook, &files, &mut updates).await?;
+            // The hook may have dropped new todos
+            let added: HashSet<usize> = updates
+                .iter()
+                .flat_map(|(_, changes)| &changes.0)
+                .filter(|c| c.kind == ChangeKind::Added)
+                .map(|c| c.id)
+                .collect();
+            new_todos.retain(|t| added.contains(&t.id));
+        }
+
+        let mut outcomes = Vec::new();
+        let mut applied = Plan::default();
+        for (mut file, (content, changes)) in files.into_iter().zip(updates) {
+            let written = content != file.original;
+            if self.config.hooks.after_write.is_some() {
+                let planned = PlannedFile::new(
+                    file.path.clone(),
+                    changes.counts(),
+                    &file.original,
+                    &content,
+                );
+                applied.files.push(PlannedFile { written, ..planned });
+            }
+            if written {
+                self.write(&file.path, &mut file.file, &content)
+                    .await
+                    .map_err(|e| e.with_path(&file.path))?;
+            } else {
+                info!("No changes, leaving {} untouched", file.path.display());
+            }
+            outcomes.push(FileOutcome {
+                path: file.path,
+                changes,
+                content,
+                written,
+            });
+        }
+
+        if state.user.as_ref().is_some_and(|last| last.id != user.id) {
+            state.max_todo_id = None;
+        }
+        state.user = Some(user);
+        // A refresh doesn't see every new todo, so it can't tell which are known
+        if scope.is_none() {
+            state.max_todo_id = state.max_todo_id.max(max_id);
+        }
+        cache.extend(self.api.response_cache());
+        state.response_cache = cache;
+        self.save_state(&state).await?;
+        // The files are written, so a failure can't abort the sync anymore
+        if let Some(hook) = &self.config.hooks.after_write {
+            if outcomes.iter().any(|f| f.written) {
+                if let Err(e) = hooks::run("after_write", hook, Some(&applied)).await {
+                    warn!("Ignoring the failure of the after_write hook: {e}");
+                }
+            }
+        }
+        if let Some(config) = &self.config.notifications {
+            notifier::notify(config, &self.config.body, &new_todos).await;
+        }
+        Ok(SyncOutcome {
+            files: outcomes,
+            pushed: pushed.len(),
+            new_todos,
+            fetched,
+        })
+    }
+
+    /// Runs the `after_merge` hook with the planned `updates` of `files`, and replaces them with
+    /// the ones it printed if any, unless they delete too many synced todos. The changes of those
+    /// files are then the ones from their original content to the printed one
+    async fn run_after_merge(
+        &self,
+        hook: &[String],
+        files: &[TodoFile],
+        updates: &mut [Update],
+    ) -> AppResult<()> {
+        let plan = Plan {
+            files: files
+                .iter()
+                .zip(updates.iter())
+                .map(|(file, (content, changes))| {
+                    PlannedFile::new(file.path.clone(), changes.counts(), &file.original, content)
+                })
+                .collect(),
+        };
+        let Some(modified) = hooks::run("after_merge", hook, Some(&plan)).await? else {
+            return Ok(());
+        };
+        let mut hooked = Vec::new();
+        for planned in modified.files {
+            let Some(i) = files.iter().position(|f| f.path == planned.path) else {
+                return Err(ParseError::new(format!(
+                    "The after_merge hook planned changes to {}, which isn't synced",
+                    planned.path.display()
+                ))
+                .into());
+            };
+            let todos = self
+                .read_existing(&planned.path, planned.content().as_bytes())
+                .await
+                .map_err(|e| e.with_path(&planned.path))?;
+            let content = self.render(&todos).await?;
+            let changes = self.changes_between(&files[i].original, &content, &updates[i].1);
+            updates[i] = (content, changes);
+            hooked.push(i);
+        }
+        if self.force {
+            return Ok(());
+        }
+        let mut kept = HashSet::new();
+        for (file, (content, _)) in files.iter().zip(updates.iter()) {
+            kept.extend(self.synced_ids(&self.read_existing(&file.path, content).await?));
+        }
+        for file in hooked.into_iter().map(|i| &files[i]) {
+            let original = self.read_existing(&file.path, &file.original).await?;
+            let synced = self.synced_ids(&original);
+            let deleted = synced.difference(&kept).count();
+            check_deletions(&self.config, deleted, synced.len()).inspect_err(|_| {
+                let path = file.path.display();
+                warn!("Too many deletions in {path} by the after_merge hook")
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Checks the todo files for duplicated ids, synced lines without an id or with an invalid
+    /// one, and lines of todos that aren't the user's, fetching every todo to find those. With
+    /// `repair`, fixes them and rewrites the files, see [`doctor::repair`]
+    pub async fn doctor(&self, repair: Option<Repair>) -> AppResult<Vec<Diagnosis>> {
+        let state = self.load_state().await?;
+        self.check_user(&state).await?;
+        let gltodos = self.api.get_all_todos(&TodoQuery::default()).await?;
+        let known: HashSet<usize> = gltodos.iter().map(|t| t.id).collect();
+        let context = self.config.context_tag.as_deref();
+
+        let mut diagnoses = Vec::new();
+        for path in self.config.todo_files() {
+            let mut file = self.open(path).await?;
+            let problems = doctor::diagnose(&file.existing, context, Some(&known));
+            let mut repaired = false;
+            if let Some(repair) = repair.filter(|_| !problems.is_empty()) {
+                let todos = doctor::repair(file.existing, context, Some(&known), repair);
+                let content = self.render(&todos).await?;
+                self.write(path, &mut file.file, &content)
+                    .await
+                    .map_err(|e| e.with_path(path))?;
+                repaired = true;
+            }
+            diagnoses.push(Diagnosis {
+                path: file.path,
+                problems,
+                repaired,
+            });
+        }
+        Ok(diagnoses)
+    }
+
+    /// Logs the problems of the todos read from a file, and repairs them if the config says so.
+    /// Unknown ids can't be told apart from deleted todos here, so they aren't checked
+    fn check(&self, path: &Path, todos: Vec<Todo>) -> Vec<Todo> {
+        let context = self.config.context_tag.as_deref();
+        let problems = doctor::diagnose(&todos, context, None);
+        for problem in &problems {
+            warn!("{problem} in {}", path.display());
+        }
+        match self.config.repair {
+            Some(repair) if !problems.is_empty() => {
+                info!(
+                    "Repairing {} problems in {}",
+                    problems.len(),
+                    path.display()
+                );
+                doctor::repair(todos, context, None, repair)
+            }
+            _ => todos,
+        }
+    }
+
+    async fn open(&self, path: &Path) -> AppResult<TodoFile> {
+        async {
+            let mut file = File::options()
+                .read(true)
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)
+                .await?;
+            let mut original = Vec::new();
+            file.read_to_end(&mut original).await?;
+            let existing = self.read_existing(path, &original).await?;
+            Ok(TodoFile {
+                path: path.to_owned(),
+                file,
+                original,
+                existing,
+            })
+        }
+        .await
+        .map_err(|e: Error| e.with_path(path))
+    }
+
+    /// The state saved by the last sync, or an empty one when replaying, as it belongs to the
+    /// recorded account rather than this one
+    async fn load_state(&self) -> AppResult<SyncState> {
+        if self.replay {
+            return Ok(SyncState::default());
+        }
+        SyncState::load(&self.config.state_file).await
+    }
+
+    async fn save_state(&self, state: &SyncState) -> AppResult<()> {
+        if self.replay {
+            info!(
+                "Replaying, leaving {} untouched",
+                self.config.state_file.display()
+            );
+            return Ok(());
+        }
+        state.save(&self.config.state_file).await
+    }
+
+    /// Fetches the user the token belongs to, and checks that it's the one from the config and
+    /// the one the file was last synced for
+    async fn check_user(&self, state: &SyncState) -> AppResult<GitlabUser> {
+        let user = self.api.get_current_user().await?;
+        let expected = match (&self.config.username, &state.user) {
+            (Some(name), _) if name != &user.username => Some(format!("the config is for {name}")),
+            (_, Some(last)) if last.id != user.id => {
+                Some(format!("the file was last synced for {}", last.username))
+            }
+            _ => None,
+        };
+        match expected {
+            Some(expected) if !self.force => Err(AbortReason::UserMismatch {
+                current: user.username,
+                expected,
+            }
+            .into()),
+            _ => Ok(user),
+        }
+    }
+
+    /// Lowest Gitlab id of the done todos needed to update the file, or `None` if every done
+    /// todo must be fetched: on the first sync for this user, with [`Self::with_full_fetch`], or
+    /// when done todos are ignored anyway.
+    ///
+    /// Gitlab ids increase with creation, so the needed todos are the synced ones still pending
+    /// in the file, which may have been marked as done since, and with [`DonePolicy::Add`] the
+    /// ones created since the last sync.
+    pub fn done_since<'a>(
+        &self,
+        existing: impl IntoIterator<Item = &'a Todo>,
+        state: &SyncState,
+        user: &GitlabUser,
+    ) -> Option<usize> {
+        if self.full || self.config.done_todo_policy == DonePolicy::Ignore {
+            return None;
+        }
+        if state.user.as_ref().map(|u| u.id) != Some(user.id) {
+            return None;
+        }
+        let next_new = state.max_todo_id? + 1;
+        let pending = existing
+            .into_iter()
+            .filter(|t| !t.done && self.is_synced(t))
+            .filter_map(|t| t.get_data("id")?.parse().ok());
+        let since = pending.min().map_or(next_new, |id: usize| id.min(next_new));
+        info!("Fetching done todos since #{since}");
+        Some(since)
+    }
+
+    /// Fetches the todos from Gitlab, skipping done ones if they are ignored by the config, and
+    /// done ones older than `done_since` if set, and the ones outside of `scope` if set. Only
+    /// the todos matching the filters of the config are returned.
+    ///
+    /// Otherwise either every todo is fetched or this fails: [`update_todos`] deletes the synced
+    /// todos that aren't in its input, so it must never be given a partial list.
+    pub async fn fetch(
+        &self,
+        done_since: Option<usize>,
+        scope: Option<&Scope>,
+    ) -> AppResult<Vec<GitlabTodo>> {
+        let mut query = self.todo_query().await?;
+        if let Some(scope) = scope {
+            query.project_id = Some(scope.project_id);
+            query.target_type = Some(scope.target_type.clone());
+        }
+        let gltodos = if let DonePolicy::Ignore = self.config.done_todo_policy {
+            self.api.get_pending_todos(&query).await?
+        } else if let Some(since) = done_since {
+            let (pending, done) = tokio::try_join!(
+                self.api.get_pending_todos(&query),
+                self.api.get_done_todos_since(&query, since)
+            )?;
+            [pending, done].concat()
+        } else {
+            self.api.get_all_todos(&query).await?
+        };
+        let fetched = gltodos.len();
+        let gltodos: Vec<_> = gltodos
+            .into_iter()
+            .filter(|t| self.config.filters.matches(t))
+            .filter(|t| scope.is_none_or(|s| s.matches(t)))
+            .collect();
+        if gltodos.len() < fetched {
+            info!("Filtered out {} todos", fetched - gltodos.len());
+        }
+        Ok(gltodos)
+    }
+
+    /// The file a fetched todo is synced to: [`AppConfig::snoozed_file`] if it is among the
+    /// `snoozed` ones, or the one of its route
+    fn todo_file_for(&self, todo: &GitlabTodo, snoozed: &HashSet<usize>) -> &Path {
+        match &self.config.snoozed_file {
+            Some(file) if snoozed.contains(&todo.id) => file,
+            _ => self.config.todo_file_for(todo),
+        }
+    }
+
+    /// Hides the synced todo with the given Gitlab id until a date, with a threshold date, then
+    /// syncs, which moves it to [`AppConfig::snoozed_file`] if set
+    pub async fn snooze(&self, id: usize, until: Date) -> AppResult<SyncOutcome> {
+        let mut todo = None;
+        for path in self.config.todo_files() {
+            let file = self.open(path).await?;
+            todo = todo
+                .or(file.existing.into_iter().find(|t| {
+                    self.is_synced(t) && t.get_data("id") == Some(id.to_string().as_str())
+                }));
+        }
+        let mut todo =
+            todo.ok_or_else(|| ParseError::new(format!("No synced todo has the id {id}")))?;
+        todo.set_data(THRESHOLD_TAG, until.to_string());
+        info!("Snoozing todo #{id} until {until}");
+        self.edit_todos(&[todo]).await
+    }
+
+    /// Replaces the synced todos of the files by the ones of `edited` with the same id, e.g.
+    /// after changing them in the [triage UI](crate::tui), then syncs
+    pub async fn edit_todos(&self, edited: &[Todo]) -> AppResult<SyncOutcome> {
+        let edited: HashMap<&str, &Todo> = edited
+            .iter()
+            .filter_map(|t| Some((t.get_data("id")?, t)))
+            .collect();
+        for path in self.config.todo_files() {
+            let mut file = self.open(path).await?;
+            let mut changed = false;
+            for todo in &mut file.existing {
+                let id = todo.get_data("id").filter(|_| self.is_synced(todo));
+                if let Some(&new) = id.and_then(|id| edited.get(id)) {
+                    changed |= todo != new;
+                    *todo = new.clone();
+                }
+            }
+            if changed {
+                let content = self.render(&file.existing).await?;
+                self.write(path, &mut file.file, &content)
+                    .await
+                    .map_err(|e| e.with_path(path))?;
+            }
+        }
+        self.run().await
+    }
+
+    /// Fetches the pending todos matching the filters of the config, without touching the files
+    pub async fn pending_todos(&self) -> AppResult<Vec<GitlabTodo>> {
+        let query = self.todo_query().await?;
+        let gltodos = self.api.get_pending_todos(&query).await?;
+        Ok(gltodos
+            .into_iter()
+            .filter(|t| !t.is_done() && self.config.filters.matches(t))
+            .collect())
+    }
+
+    /// Translates the criteria of the filters that Gitlab can apply to API parameters, looking
+    /// up the ids of the project, group and author they name
+    async fn todo_query(&self) -> AppResult<TodoQuery> {
+        let Some(filter) = self.config.filters.server_side() else {
+            return Ok(TodoQuery::default());
+        };
+        fn literal(glob: &Option<Glob>) -> Option<&str> {
+            glob.as_ref().and_then(Glob::literal)
+        }
+        let mut query = TodoQuery {
+            action: literal(&filter.action).map(Into::into),
+            target_type: literal(&filter.target_type).map(Into::into),
+            ..Default::default()
+        };
+        if let Some(path) = literal(&filter.project) {
+            let id = self.api.get_project_id(path).await;
+            query.project_id = Some(id.map_err(|e| filter_not_found(e, "project", path))?);
+        }
+        if let Some(path) = literal(&filter.group) {
+            let id = self.api.get_group_id(path).await;
+            query.group_id = Some(id.map_err(|e| filter_not_found(e, "group", path))?);
+        }
+        if let Some(username) = literal(&filter.author) {
+            let user = self.api.get_user(username).await?.ok_or_else(|| {
+                ConfigError::Other(format!(
+                    "The user {username} from the filters doesn't exist"
+                ))
+            })?;
+            query.author_id = Some(user.id);
+        }
+        debug!("Server-side filters: {query:?}");
+        Ok(query)
+    }
+
+    /// Marks as done on Gitlab the pending todos that are done in the files, replacing them in
+    /// `gltodos` by their updated version so that the files keep them done. Returns the ids of
+    /// the todos marked as done
+    pub async fn push_completed<'a>(
+        &self,
+        existing: impl IntoIterator<Item = &'a Todo>,
+        gltodos: &mut [GitlabTodo],
+    ) -> AppResult<HashSet<usize>> {
+        let completed: HashSet<usize> = existing
+            .into_iter()
+            .filter(|t| t.done && self.is_synced(t))
+            .filter_map(|t| t.get_data("id")?.parse().ok())
+            .collect();
+        let to_push: Vec<_> = gltodos
+            .iter_mut()
+            .filter(|t| !t.is_done() && completed.contains(&t.id))
+            .collect();
+        let pushes = to_push.iter().map(|t| self.api.mark_todo_as_done(t.id));
+        let updated = try_join_all(pushes).await?;
+        let mut pushed = HashSet::new();
+        for (todo, updated) in to_push.into_iter().zip(updated) {
+            info!("Marked todo #{} as done on Gitlab", todo.id);
+            pushed.insert(todo.id);
+            *todo = updated;
+        }
+        Ok(pushed)
+    }
+
+    /// Converts fetched todos to todo.txt items, indexed by their Gitlab id, adding the tags of
+    /// their route
+    pub fn convert(&self, gltodos: Vec<GitlabTodo>) -> AppResult<HashMap<usize, Todo>> {
+        gltodos
+            .into_iter()
+            .map(|gl| -> AppResult<(usize, Todo)> {
+                let id = gl.id;
+                let route = self.config.route(&gl);
+                let mut todo = gl.into_todo(&self.config)?;
+                if let Some(route) = route {
+                    for project in &route.projects {
+                        todo += DescriptionPart::Project(project);
+                    }
+                    for context in &route.contexts {
+                        todo += DescriptionPart::Context(context);
+                    }
+                }
+                Ok((id, todo))
+            })
+            .collect()
+    }
+
+    pub async fn read_existing(&self, path: &Path, content: &[u8]) -> AppResult<Vec<Todo>> {
+        let existing = Todo::read_file(content).await?;
+        info!(
+            "Read {} existing todos from {}",
+            existing.len(),
+            path.display()
+        );
+        Ok(existing)
+    }
+
+    /// Updates the synced todos among `existing` with the fetched ones, leaving the others
+    /// untouched. `partial_done` tells that only some done todos were fetched, see
+    /// [`Self::done_since`], and `scope` that only the todos in it were. Returns the resulting
+    /// list of todos along with the changes from [`update_todos`]
+    pub fn merge(
+        &self,
+        existing: Vec<Todo>,
+        todos: HashMap<usize, Todo>,
+        partial_done: bool,
+        scope: Option<&Scope>,
+    ) -> (Vec<Todo>, Changes) {
+        let (mut existing, other): (Vec<_>, _) = existing
+            .into_iter()
+            .partition(|t| self.is_synced(t) && scope.is_none_or(|s| s.contains(t)));
+        for todo in &existing {
+            if let Err(e) = todo.validate() {
+                warn!("{e} in todo '{todo}'");
+            }
+        }
+        let changes = update_todos(
+            &mut existing,
+            todos,
+            self.config.done_todo_policy == DonePolicy::Add,
+            partial_done,
+        );
+        if self.config.hide_future_threshold {
+            hide_future_threshold(&mut existing, &Date::today());
+        }
+        ([other, existing].concat(), changes)
+    }
+
+    /// Whether a todo from the file is managed by the sync, according to the context tag
+    pub fn is_synced(&self, todo: &Todo) -> bool {
+        self.config
+            .context_tag
+            .as_ref()
+            .map(|ctx| todo.has_context(ctx))
+            .unwrap_or(true)
+    }
+
+    /// The changes to the synced todos from the `original` content of a file to `content`, e.g.
+    /// as modified by a hook. The `planned` changes whose result is still the same are kept, as
+    /// they tell conflicts apart from updates, and so are the todos pushed to Gitlab
+    fn changes_between(&self, original: &[u8], content: &[u8], planned: &Changes) -> Changes {
+        let (original, content) = (hooks::lines(original), hooks::lines(content));
+        let synced = |lines: Vec<String>| -> Vec<(usize, String)> {
+            lines
+                .into_iter()
+                .filter_map(|line| {
+                    let todo: Todo = line.parse().ok()?;
+                    let id = todo.get_data("id")?.parse().ok()?;
+                    self.is_synced(&todo).then_some((id, line))
+                })
+                .collect()
+        };
+        let removed = synced(hooks::missing(&original, &content));
+        let added = synced(hooks::missing(&content, &original));
+
+        let mut changes = Vec::new();
+        let before: HashMap<usize, &String> = removed.iter().map(|(id, l)| (*id, l)).collect();
+        for (id, line) in &added {
+            let kept = planned.0.iter().find(|c| {
+                c.id == *id && c.kind != ChangeKind::Pushed && c.after.as_ref() == Some(line)
+            });
+            changes.push(kept.cloned().unwrap_or_else(|| TodoChange {
+                kind: if before.contains_key(id) {
+                    ChangeKind::Updated
+                } else {
+                    ChangeKind::Added
+                },
+                id: *id,
+                before: before.get(id).map(|l| l.to_string()),
+                after: Some(line.clone()),
+            }));
+        }
+        let after: HashSet<usize> = added.iter().map(|(id, _)| *id).collect();
+        for (id, line) in removed.into_iter().filter(|(id, _)| !after.contains(id)) {
+            changes.push(TodoChange {
+                kind: ChangeKind::Removed,
+                id,
+                before: Some(line),
+                after: None,
+            });
+        }
+        let pushed = planned.0.iter().filter(|c| c.kind == ChangeKind::Pushed);
+        changes.extend(pushed.cloned());
+        Changes(changes)
+    }
+
+    /// Ids of the synced todos among `todos`
+    fn synced_ids(&self, todos: &[Todo]) -> HashSet<usize> {
+        todos
+            .iter()
+            .filter(|t| self.is_synced(t))
+            .filter_map(|t| t.get_data("id")?.parse().ok())
+            .collect()
+    }
+
+    /// Formats todos as the content of a todo file
+    pub async fn render(&self, todos: &[Todo]) -> AppResult<Vec<u8>> {
+        let mut buf: Vec<u8> = Vec::new();
+        Todo::write_file(&mut buf, todos.iter()).await?;
+        info!("Rendered {} todos ({} bytes)", todos.len(), buf.len());
+        Ok(buf)
+    }
+
+    /// Replaces the content of a todo file
+    pub async fn write(&self, path: &Path, tf: &mut File, content: &[u8]) -> AppResult<()> {
+        info!("Writing {}", path.display());
+        tf.set_len(0).await?; // Truncate file
+        tf.seek(SeekFrom::Start(0)).await?;
+        tf.write_all(content).await?;
+        tf.flush().await?;
+        Ok(())
+    }
+}
+
+impl Scope {
+    /// Whether a todo from a file is in this scope
+    pub fn contains(&self, todo: &Todo) -> bool {
+        todo.has_project(&self.project_path)
+            && todo
+                .description
+                .starts_with(&format!("[{}:", self.target_type))
+    }
+
+    /// Whether a fetched todo is in this scope
+    pub fn matches(&self, todo: &GitlabTodo) -> bool {
+        todo.project.as_deref() == Some(self.project_path.as_str())
+            && todo.target_type == self.target_type
+    }
+}
+
+impl Changes {
+    /// Number of new, updated (including conflicts) and deleted todos
+    pub fn counts(&self) -> (usize, usize, usize) {
+        (
+            self.count(ChangeKind::Added),
+            self.count(ChangeKind::Updated) + self.count(ChangeKind::Conflict),
+            self.count(ChangeKind::Removed),
+        )
+    }
+
+    pub fn count(&self, kind: ChangeKind) -> usize {
+        self.0.iter().filter(|c| c.kind == kind).count()
+    }
+}
+
+/// The changes of the todos among `existing` that were `pushed` to Gitlab
+fn pushed_changes(existing: &[Todo], pushed: &HashSet<usize>) -> Vec<TodoChange> {
+    existing
+        .iter()
+        .filter_map(|t| {
+            let id = t.get_data("id")?.parse().ok()?;
+            pushed.contains(&id).then(|| TodoChange {
+                kind: ChangeKind::Pushed,
+                id,
+                before: Some(t.to_string()),
+                after: None,
+            })
+        })
+        .collect()
+}
+
+/// Reports a missing project or group named by the filters as a config error
+fn filter_not_found(e: Error, what: &str, name: &str) -> Error {
+    match e {
+        Error::Api(e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+            ConfigError::Other(format!("The {what} {name} from the filters doesn't exist")).into()
+        }
+        e => e,
+    }
+}
+
+/// Checks that the number of deleted todos is within the limits of the config
+fn check_deletions(config: &AppConfig, deleted: usize, synced: usize) -> Result<(), AbortReason> {
+    let abort = |limit| AbortReason::TooManyDeletions {
+        deleted,
+        synced,
+        limit,
+    };
+    if let Some(max) = config.max_deletions.filter(|max| deleted > *max) {
+        return Err(abort(format!("the limit of {max}")));
+    }
+    if let Some(percent) = config.max_deletion_percent {
+        if synced >= MIN_TODOS_FOR_DELETION_PERCENT
+            && deleted as f64 > synced as f64 * percent / 100.0
+        {
+            return Err(abort(format!("the limit of {percent}%")));
+        }
+    }
+    Ok(())
+}
+
+/// Updates `existing` synced todos with the fetched `todos`, indexed by Gitlab id: todos are
+/// replaced by their fetched version, removed if they weren't fetched, and fetched todos that
+/// weren't present are added (done ones only if `add_done`). Extension tags and priorities set by
+/// the user on existing todos are kept, the priority moving to the `pri:` tag once done. If
+/// `keep_done`, existing done todos that weren't fetched are kept, for when only recent done
+/// todos were fetched. New todos are added in the order of their ids.
+///
+/// Returns the changes, where a todo done in the file but pending on Gitlab is a conflict.
+pub fn update_todos(
+    existing: &mut Vec<Todo>,
+    mut todos: HashMap<usize, Todo>,
+    add_done: bool,
+    keep_done: bool,
+) -> Changes {
+    // Lines without a valid id are reported by doctor::diagnose
+    fn get_id(t: &Todo) -> Option<usize> {
+        t.get_data("id")?.parse().ok()
+    }
+    let mut changes = Vec::new();
+    existing.retain_mut(|extd| {
+        if let Some(id) = get_id(extd) {
+            if let Some(mut td) = todos.remove(&id) {
+                keep_user_data(&mut td, extd);
+                if extd != &td {
+                    let kind = match extd.done && !td.done {
+                        true => ChangeKind::Conflict,
+                        false => ChangeKind::Updated,
+                    };
+                    changes.push(TodoChange {
+                        kind,
+                        id,
+                        before: Some(extd.to_string()),
+                        after: Some(td.to_string()),
+                    });
+                    *extd = td;
+                }
+            } else if !(keep_done && extd.done) {
+                changes.push(TodoChange {
+                    kind: ChangeKind::Removed,
+                    id,
+                    before: Some(extd.to_string()),
+                    after: None,
+                });
+                return false;
+            }
+        }
+        true
+    });
+    let mut added: Vec<(usize, Todo)> = todos
+        .into_iter()
+        .filter(|(_, t)| add_done || !t.done)
+        .collect();
+    added.sort_by_key(|(id, _)| *id);
+    for (id, todo) in added {
+        changes.push(TodoChange {
+            kind: ChangeKind::Added,
+            id,
+            before: None,
+            after: Some(todo.to_string()),
+        });
+        existing.push(todo);
+    }
+    Changes(changes)
+}
+
+/// Copies the extension tags and priority set by the user on an existing todo to its fetched
+/// version, the priority moving to the `pri:` tag if it's done
+fn keep_user_data(fetched: &mut Todo, existing: &Todo) {
+    fetched.copy_data_from(existing, &EXTENSION_TAGS);
+    match existing.priority {
+        Some(pri) if fetched.done && fetched.get_data(PRIORITY_TAG).is_none() => {
+            fetched.set_data(PRIORITY_TAG, pri.to_string())
+        }
+        Some(_) if !fetched.done => fetched.priority = existing.priority,
+        _ => {}
+    }
+}
+
+fn hide_future_threshold(todos: &mut [Todo], today: &Date) {
+    for todo in todos {
+        match todo.threshold() {
+            Ok(Some(threshold)) => todo.set_hidden(&threshold > today),
+            Ok(None) => {}
+            Err(e) => warn!("{e} in todo '{todo}'"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+    #[test]
+    fn test_done_policy() {
+        let t1 = Todo::new(false, None, None, None, "Test 1 id:1 +test".to_string()).unwrap();
+        let t2 = Todo::new(false, None, None, None, "Test 2 id:2 +test".to_string()).unwrap();
+        let t3 = Todo::new(false, None, None, None, "Test 3 id:3 +test".to_string()).unwrap();
+        let mut t1d = t1.clone();
+        t1d.done = true;
+        let mut t2d = t2.clone();
+        t2d.done = true;
+        let mut t3d = t3.clone();
+        t3d.done = true;
+
+        fn test(mut existing: Vec<Todo>, todos: Vec<Todo>, result: &[Todo], add_done: bool) {
+            update_todos(&mut existing, map_of(todos), add_done, false);
+            let existing: HashSet<(&str, bool)> =
+                HashSet::from_iter(existing.iter().map(|t| (t.get_data("id").unwrap(), t.done)));
+            let result =
+                HashSet::from_iter(result.iter().map(|t| (t.get_data("id").unwrap(), t.done)));
+            assert_eq!(
+                existing, result,
+                "Testing update_todos with add_done = {:?}",
+                add_done
+            );
+        }
+
+        test(
+            vec![],
+            vec![t1d.clone(), t2d.clone()],
+            &[t1d.clone(), t2d.clone()],
+            true,
+        );
+
+        test(
+            vec![t1.clone()],
+            vec![t1d.clone(), t2.clone(), t3d.clone()],
+            &[t1d.clone(), t2.clone()],
+            false,
+        );
+    }
+
+    #[test]
+    fn test_extension_tags_sync() {
+        let synced = Todo::new(false, None, None, None, "Test id:1 t:2024-01-10".into()).unwrap();
+        let fetched = Todo::new(false, None, None, None, "Test 2 id:1".into()).unwrap();
+        let mut existing = vec![synced];
+        update_todos(&mut existing, map_of([fetched]), false, false);
+        assert_eq!(existing[0].description, "Test 2 id:1 t:2024-01-10");
+
+        existing[0].priority = Some('A');
+        let fetched = Todo::new(false, None, None, None, "Test 2 id:1".into()).unwrap();
+        update_todos(&mut existing, map_of([fetched.clone()]), false, false);
+        assert_eq!(existing[0].to_string(), "(A) Test 2 id:1 t:2024-01-10");
+        let done = Todo {
+            done: true,
+            ..fetched
+        };
+        update_todos(&mut existing, map_of([done]), false, false);
+        assert_eq!(existing[0].to_string(), "x Test 2 id:1 t:2024-01-10 pri:A");
+
+        hide_future_threshold(&mut existing, &"2024-01-09".parse().unwrap());
+        assert!(existing[0].is_hidden());
+        hide_future_threshold(&mut existing, &"2024-01-10".parse().unwrap());
+        assert!(!existing[0].is_hidden());
+    }
+
+    #[test]
+    fn test_keep_done() {
+        let pending = Todo::new(false, None, None, None, "Pending id:3".into()).unwrap();
+        let done = Todo::new(true, None, None, None, "Done id:1".into()).unwrap();
+        let gone = Todo::new(false, None, None, None, "Gone id:2".into()).unwrap();
+        let mut existing = vec![done.clone(), gone, pending.clone()];
+        let changes = update_todos(&mut existing, map_of([pending.clone()]), false, true);
+        assert_eq!(
+            changes.0,
+            [TodoChange {
+                kind: ChangeKind::Removed,
+                id: 2,
+                before: Some("Gone id:2".into()),
+                after: None,
+            }]
+        );
+        assert_eq!(existing, [done, pending]);
+    }
+
+    #[test]
+    fn test_routes() {
+        let mut config = AppConfig {
+            todo_file: "todo.txt".into(),
+            context_tag: Some("gitlab".into()),
+            ..Default::default()
+        };
+        config.routes = serde_json::from_value(serde_json::json!([
+            {"match": {"project": "infra/*"}, "todo_file": "infra.txt", "contexts": ["infra"]},
+            {"match": {"group": "infra"}, "todo_file": "other.txt"},
+            {"match": {"project": "web/*"}, "projects": ["web"]},
+        ]))
+        .unwrap();
+        let todo = |id: usize, project: &str| -> GitlabTodo {
+            serde_json::from_value(serde_json::json!({
+                "id": id,
+                "body": "Body",
+                "state": "pending",
+                "created_at": "2024-01-01T00:00:00.000Z",
+                "updated_at": "2024-01-01T00:00:00.000Z",
+                "action_name": "assigned",
+                "target_type": "Issue",
+                "project": {"path_with_namespace": project},
+                "target_url": "https://git.example/issues/1",
+            }))
+            .unwrap()
+        };
+        let files: Vec<_> = config.todo_files().iter().map(|p| p.to_owned()).collect();
+        assert_eq!(
+            files,
+            [
+                Path::new("todo.txt"),
+                "infra.txt".as_ref(),
+                "other.txt".as_ref()
+            ]
+        );
+        assert_eq!(
+            config.todo_file_for(&todo(1, "infra/ci")),
+            Path::new("infra.txt")
+        );
+        assert_eq!(
+            config.todo_file_for(&todo(1, "web/app")),
+            Path::new("todo.txt")
+        );
+
+        let engine = SyncEngine::new(config).unwrap();
+        let todos = engine
+            .convert(vec![todo(1, "infra/ci"), todo(2, "web/app")])
+            .unwrap();
+        assert_eq!(
+            todos[&1].description,
+            "[Issue:assigned] Body +infra/ci id:1 @gitlab @infra"
+        );
+        assert_eq!(
+            todos[&2].description,
+            "[Issue:assigned] Body +web/app id:2 @gitlab +web"
+        );
+    }
+
+    #[test]
+    fn test_scoped_merge() {
+        let engine = SyncEngine::new(AppConfig::default()).unwrap();
+        let scope = Scope {
+            project_id: 1,
+            project_path: "main/app".into(),
+            target_type: "Issue".into(),
+        };
+        let todo = |desc: &str| Todo::new(false, None, None, None, desc.into()).unwrap();
+        let in_scope = todo("[Issue:assigned] Fix +main/app id:1");
+        let other_type = todo("[MergeRequest:assigned] Review +main/app id:2");
+        let other_project = todo("[Issue:assigned] Fix +main/web id:3");
+        assert!(scope.contains(&in_scope));
+        assert!(!scope.contains(&other_type));
+        assert!(!scope.contains(&other_project));
+
+        let existing = vec![other_type.clone(), in_scope, other_project.clone()];
+        let (todos, changes) = engine.merge(existing, HashMap::new(), false, Some(&scope));
+        assert_eq!(changes.counts(), (0, 0, 1));
+        assert_eq!(todos, [other_type, other_project]);
+    }
+
+    #[test]
+    fn test_check_deletions() {
+        let mut config = AppConfig::default();
+        assert!(check_deletions(&config, 5, 10).is_ok());
+        assert!(check_deletions(&config, 6, 10).is_err());
+        assert!(check_deletions(&config, 9, 9).is_ok());
+        config.max_deletions = Some(3);
+        assert!(check_deletions(&config, 3, 100).is_ok());
+        let err = check_deletions(&config, 4, 100).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "it would delete 4 of the 100 synced todos, more than the limit of 3"
+        );
+        config.max_deletion_percent = None;
+        config.max_deletions = None;
+        assert!(check_deletions(&config, 100, 100).is_ok());
+    }
+
+    fn map_of(tds: impl IntoIterator<Item = Todo>) -> HashMap<usize, Todo> {
+        HashMap::from_iter(
+            tds.into_iter()
+                .map(|t| (t.get_data("id").unwrap().parse().unwrap(), t)),
+        )
+    }
+
+    /// A line of a todo file: one that isn't synced, or the synced version of a Gitlab todo
+    #[derive(Debug, Clone)]
+    enum Line {
+        Other(String),
+        Synced(usize, GitlabSnapshot),
+    }
+
+    /// The state of a Gitlab todo: whether it's done, a variant of its body, and whether the
+    /// user added a threshold date to its line
+    type GitlabSnapshot = (bool, u8, bool);
+
+    fn gitlab_todo(id: usize, (done, body, _): GitlabSnapshot) -> GitlabTodo {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "body": format!("Body {body}"),
+            "state": if done { "done" } else { "pending" },
+            "created_at": "2024-01-01T00:00:00.000Z",
+            "updated_at": "2024-01-02T00:00:00.000Z",
+            "action_name": "assigned",
+            "target_type": "Issue",
+            "project": {"path_with_namespace": "main/app"},
+            "target_url": "https://git.example/issues/1",
+        }))
+        .unwrap()
+    }
+
+    fn snapshot() -> impl Strategy<Value = GitlabSnapshot> {
+        (any::<bool>(), 0..3u8, any::<bool>())
+    }
+
+    /// A todo file mixing lines that aren't synced with synced ones, which have unique ids
+    fn todo_file() -> impl Strategy<Value = Vec<Line>> {
+        let others = prop::collection::vec("[a-z]{1,8}( \\+[a-z]{1,5})?", 0..5);
+        let synced = prop::collection::btree_map(1..20usize, snapshot(), 0..10);
+        (others, synced).prop_flat_map(|(others, synced)| {
+            let lines: Vec<Line> = others
+                .into_iter()
+                .map(Line::Other)
+                .chain(synced.into_iter().map(|(id, s)| Line::Synced(id, s)))
+                .collect();
+            Just(lines).prop_shuffle()
+        })
+    }
+
+    fn policy() -> impl Strategy<Value = DonePolicy> {
+        prop_oneof![
+            Just(DonePolicy::Mark),
+            Just(DonePolicy::Add),
+            Just(DonePolicy::Ignore)
+        ]
+    }
+
+    fn synced_ids(todos: &[Todo]) -> Vec<usize> {
+        let synced = todos.iter().filter(|t| t.has_context("gitlab"));
+        synced
+            .map(|t| t.get_data("id").unwrap().parse().unwrap())
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn prop_merge_invariants(
+            lines in todo_file(),
+            fetched in prop::collection::btree_map(1..20usize, snapshot(), 0..12),
+            policy in policy(),
+            partial_done: bool,
+        ) {
+            let config = AppConfig {
+                context_tag: Some("gitlab".into()),
+                done_todo_policy: policy.clone(),
+                ..Default::default()
+            };
+            let engine = SyncEngine::new(config.clone()).unwrap();
+            // Done todos are never fetched incrementally with this policy
+            let partial_done = partial_done && policy != DonePolicy::Ignore;
+            let existing: Vec<Todo> = lines
+                .iter()
+                .map(|line| match line {
+                    Line::Other(text) => Todo::new(false, None, None, None, text.clone()).unwrap(),
+                    Line::Synced(id, snapshot) => {
+                        let mut todo = gitlab_todo(*id, *snapshot).into_todo(&config).unwrap();
+                        if snapshot.2 {
+                            todo += DescriptionPart::Data("t", "2030-01-01");
+                        }
+                        todo
+                    }
+                })
+                .collect();
+            let fetched: BTreeMap<usize, GitlabSnapshot> = fetched
+                .into_iter()
+                .filter(|(_, (done, _, _))| !(*done && policy == DonePolicy::Ignore))
+                .collect();
+            let fetch = || {
+                let gltodos = fetched.iter().map(|(id, s)| gitlab_todo(*id, *s)).collect();
+                engine.convert(gltodos).unwrap()
+            };
+
+            let (merged, changes) = engine.merge(existing.clone(), fetch(), partial_done, None);
+            let (new, upd, del) = changes.counts();
+
+            // Lines that aren't synced are kept as is, in the same order
+            let others = |todos: &[Todo]| -> Vec<Todo> {
+                todos.iter().filter(|t| !t.has_context("gitlab")).cloned().collect()
+            };
+            prop_assert_eq!(others(&merged), others(&existing));
+
+            let before = synced_ids(&existing);
+            let after = synced_ids(&merged);
+            let unique: BTreeSet<usize> = after.iter().copied().collect();
+            prop_assert_eq!(unique.len(), after.len(), "Duplicate ids in {:?}", after);
+
+            // The synced todos are the fetched ones, except for done ones depending on the policy
+            let expected: BTreeSet<usize> = before
+                .iter()
+                .copied()
+                .filter(|id| {
+                    let done = existing.iter().any(|t| {
+                        t.done && t.get_data("id") == Some(id.to_string().as_str())
+                    });
+                    fetched.contains_key(id) || (partial_done && done)
+                })
+                .chain(fetched.iter().filter_map(|(id, (done, _, _))| {
+                    (!done || policy == DonePolicy::Add).then_some(*id)
+                }))
+                .collect();
+            prop_assert_eq!(&unique, &expected);
+            for todo in merged.iter().filter(|t| t.has_context("gitlab")) {
+                let id: usize = todo.get_data("id").unwrap().parse().unwrap();
+                if let Some((done, body, _)) = fetched.get(&id) {
+                    prop_assert_eq!(todo.done, *done);
+                    let expected_body = format!("Body {body}");
+                    prop_assert!(todo.description.contains(&expected_body));
+                }
+            }
+
+            // The counters match the changes
+            let before: BTreeSet<usize> = before.into_iter().collect();
+            prop_assert_eq!(new, unique.difference(&before).count());
+            prop_assert_eq!(del, before.difference(&unique).count());
+            let line = |todos: &[Todo], id: usize| {
+                let id = id.to_string();
+                todos
+                    .iter()
+                    .find(|t| t.get_data("id") == Some(id.as_str()))
+                    .map(|t| t.to_string())
+            };
+            let updated = unique
+                .intersection(&before)
+                .filter(|id| line(&existing, **id) != line(&merged, **id))
+                .count();
+            prop_assert_eq!(upd, updated);
+
+            // Syncing the same todos again changes nothing
+            let (again, changes) = engine.merge(merged.clone(), fetch(), partial_done, None);
+            prop_assert_eq!(changes, Changes::default());
+            prop_assert_eq!(again, merged);
+        }
+    }
+}
@@ -1,19 +1,66 @@
-use crate::{AppResult, Error};
+use crate::error::{AppResult, Error, ParseError};
 use regex::{Captures, Regex};
 use std::borrow::{Borrow, Cow};
 use std::fmt::{Display, Formatter};
 use std::ops::{Add, AddAssign};
 use std::str::FromStr;
 use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 
-#[derive(Clone, Debug, PartialEq)]
+/// Data tag holding the due date of a todo
+pub const DUE_TAG: &str = "due";
+/// Data tag holding the threshold date of a todo, before which it shouldn't be worked on
+pub const THRESHOLD_TAG: &str = "t";
+/// Data tag holding the recurrence of a todo, see [`Recurrence`]
+pub const RECURRENCE_TAG: &str = "rec";
+/// Data tag marking a todo as hidden when its value is `1`
+pub const HIDDEN_TAG: &str = "h";
+/// Data tag holding the priority of a completed todo
+pub const PRIORITY_TAG: &str = "pri";
+/// Data tags set by todo.txt clients, which are owned by the user rather than the sync
+pub const EXTENSION_TAGS: [&str; 5] = [
+    DUE_TAG,
+    THRESHOLD_TAG,
+    RECURRENCE_TAG,
+    HIDDEN_TAG,
+    PRIORITY_TAG,
+];
+
+/// A calendar date, formatted as `YYYY-MM-DD`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Date {
     year: u16,
     month: u8,
     day: u8,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeriodUnit {
+    Days,
+    /// Days from monday to friday
+    BusinessDays,
+    Weeks,
+    Months,
+    Years,
+}
+
+/// An amount of time expressed in calendar units, as used by the `rec:` tag (e.g. `3d`, `1w`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Period {
+    pub count: u32,
+    pub unit: PeriodUnit,
+}
+
+/// Value of a `rec:` tag. A strict recurrence (`rec:+1w`) is based on the due or threshold date
+/// of the todo, a normal one (`rec:1w`) on its completion date
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Recurrence {
+    pub strict: bool,
+    pub period: Period,
+}
+
+/// A meta tag found in the description of a todo
 #[derive(Clone, Debug, PartialEq)]
 pub enum DescriptionPart<'a> {
     Project(&'a str),
@@ -21,6 +68,7 @@ pub enum DescriptionPart<'a> {
     Data(&'a str, &'a str),
 }
 
+/// A todo.txt item
 #[derive(Clone, Debug, PartialEq)]
 pub struct Todo {
     pub done: bool,
@@ -30,7 +78,6 @@ pub struct Todo {
     pub description: String,
 }
 
-#[allow(dead_code)]
 impl Todo {
     pub fn new(
         done: bool,
@@ -38,9 +85,9 @@ impl Todo {
         created: Option<Date>,
         completed: Option<Date>,
         description: String,
-    ) -> AppResult<Self> {
+    ) -> Result<Self, ParseError> {
         if completed.is_some() && created.is_none() {
-            return Err(Error::msg(
+            return Err(ParseError::new(
                 "Can't have a Todo with a completion date and not a creation date",
             ));
         }
@@ -53,21 +100,23 @@ impl Todo {
         })
     }
 
+    /// Matches whole whitespace-delimited tokens that are meta tags: `@context`, `+project` or
+    /// `key:value`, where neither the tag name nor the value may be empty
     fn part_reg() -> &'static Regex {
-        static PART_REG: LazyLock<Regex> = LazyLock::new(|| {
-            Regex::new(r"(^|\s)(?<tag>(?<head>@|\+|(?<key>\w+):)(?<body>\S)+)\b").unwrap()
-        });
-        &*PART_REG
+        static PART_REG: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"(^|\s)(?<tag>(?<head>@|\+|(?<key>\w+):)\S+)").unwrap());
+        &PART_REG
     }
 
-    pub fn find_meta(&self) -> impl Iterator<Item = DescriptionPart> {
-        Self::part_reg().captures_iter(&self.description).map(|c| {
-            DescriptionPart::parse(c.name("tag").unwrap().as_str())
-                .expect("Can't parse DescriptionPart")
-        })
+    /// The meta tags of the description. Tokens that look like tags but can't be parsed as one
+    /// are skipped
+    pub fn find_meta(&self) -> impl Iterator<Item = DescriptionPart<'_>> {
+        Self::part_reg()
+            .captures_iter(&self.description)
+            .filter_map(|c| DescriptionPart::parse(c.name("tag")?.as_str()).ok())
     }
 
-    pub fn get_tag(&self) -> Vec<DescriptionPart> {
+    pub fn get_tag(&self) -> Vec<DescriptionPart<'_>> {
         self.find_meta().collect()
     }
 
@@ -92,20 +141,175 @@ impl Todo {
             .next()
     }
 
-    pub fn escape_description(desc: &str) -> Cow<str> {
-        Self::part_reg().replace_all(desc.as_ref(), |c: &Captures| {
-            let pos = c.name("head").unwrap().end() - 1;
-            let m = &c.get(0).unwrap();
+    /// Sets the value of a data tag, replacing the first existing one with the same key or
+    /// adding a new one at the end of the description
+    pub fn set_data(&mut self, key: impl AsRef<str>, value: impl AsRef<str>) {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        let existing = Self::part_reg()
+            .captures_iter(&self.description)
+            .find(|c| c.name("key").is_some_and(|k| k.as_str() == key))
+            .map(|c| c.name("tag").unwrap().range());
+        if let Some(range) = existing {
+            self.description
+                .replace_range(range, &DescriptionPart::Data(key, value).to_string());
+        } else {
+            *self += DescriptionPart::Data(key, value);
+        }
+    }
+
+    /// Removes every data tag with the given key, returns whether any was found
+    pub fn remove_data(&mut self, key: impl AsRef<str>) -> bool {
+        let key = key.as_ref();
+        let mut removed = false;
+        let desc = Self::part_reg().replace_all(&self.description, |c: &Captures| {
+            if c.name("key").is_some_and(|k| k.as_str() == key) {
+                removed = true;
+                String::new()
+            } else {
+                c.get(0).unwrap().as_str().to_string()
+            }
+        });
+        if removed {
+            self.description = desc.trim_start().to_string();
+        }
+        removed
+    }
+
+    /// Removes every occurrence of a meta tag, returns whether any was found
+    pub fn remove_tag(&mut self, tag: DescriptionPart) -> bool {
+        let tag = tag.to_string();
+        let mut removed = false;
+        let desc = Self::part_reg().replace_all(&self.description, |c: &Captures| {
+            if c.name("tag").is_some_and(|t| t.as_str() == tag) {
+                removed = true;
+                String::new()
+            } else {
+                c.get(0).unwrap().as_str().to_string()
+            }
+        });
+        if removed {
+            self.description = desc.trim_start().to_string();
+        }
+        removed
+    }
+
+    /// Copies the given data tags from another todo, for those that aren't already set on this
+    /// one
+    pub fn copy_data_from(&mut self, other: &Todo, keys: &[&str]) {
+        for key in keys {
+            if self.get_data(key).is_none() {
+                if let Some(value) = other.get_data(key) {
+                    self.set_data(key, value);
+                }
+            }
+        }
+    }
+
+    fn parse_data<T: FromStr<Err = ParseError>>(&self, key: &str) -> Result<Option<T>, ParseError> {
+        self.get_data(key)
+            .map(|v| {
+                v.parse().map_err(|e: ParseError| {
+                    ParseError::new(format!("Invalid value for tag {key}:{v}: {}", e.message))
+                })
+            })
+            .transpose()
+    }
+
+    pub fn due(&self) -> Result<Option<Date>, ParseError> {
+        self.parse_data(DUE_TAG)
+    }
+
+    pub fn threshold(&self) -> Result<Option<Date>, ParseError> {
+        self.parse_data(THRESHOLD_TAG)
+    }
+
+    pub fn recurrence(&self) -> Result<Option<Recurrence>, ParseError> {
+        self.parse_data(RECURRENCE_TAG)
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.get_data(HIDDEN_TAG) == Some("1")
+    }
+
+    pub fn set_hidden(&mut self, hidden: bool) {
+        if hidden {
+            self.set_data(HIDDEN_TAG, "1");
+        } else {
+            self.remove_data(HIDDEN_TAG);
+        }
+    }
+
+    /// Priority stored in the `pri:` tag, which clients use to remember the priority of
+    /// completed todos
+    pub fn priority_tag(&self) -> Result<Option<char>, ParseError> {
+        self.get_data(PRIORITY_TAG)
+            .map(|v| match v.as_bytes() {
+                [p @ b'A'..=b'Z'] => Ok(*p as char),
+                _ => Err(ParseError::new(format!(
+                    "Invalid value for tag {PRIORITY_TAG}:{v}, expected a letter from A to Z"
+                ))),
+            })
+            .transpose()
+    }
+
+    /// Whether the todo has a threshold date after the given day
+    pub fn is_before_threshold(&self, today: &Date) -> Result<bool, ParseError> {
+        Ok(self.threshold()?.is_some_and(|t| &t > today))
+    }
+
+    /// Checks that all the extension tags of the todo have valid values
+    pub fn validate(&self) -> Result<(), ParseError> {
+        self.due()?;
+        self.threshold()?;
+        self.recurrence()?;
+        self.priority_tag()?;
+        if let Some(h) = self.get_data(HIDDEN_TAG) {
+            if h != "0" && h != "1" {
+                return Err(ParseError::new(format!(
+                    "Invalid value for tag {HIDDEN_TAG}:{h}, expected 0 or 1"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Escapes a text so that none of its tokens are parsed as meta tags, in a way that can be
+    /// reversed with [`Todo::unescape_description`].
+    ///
+    /// A backslash is inserted right before the `@`/`+` head of a tag (`\@ctx`, `\+prj`), or
+    /// right before the colon of a `key:value` pair (`key\:value`). Tokens that already look
+    /// like escaped tags (any number of backslashes in that position) get one more backslash,
+    /// so that unescaping gives back the original text. Everything else, including whitespace,
+    /// is left untouched.
+    pub fn escape_description(desc: &str) -> Cow<'_, str> {
+        static ESCAPE_REG: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"(^|\s)(?:(?<pre>\\*)[@+]|\w+(?<mid>\\*):)\S").unwrap());
+        ESCAPE_REG.replace_all(desc, |c: &Captures| {
+            let m = c.get(0).unwrap();
+            let pos = c.name("pre").or(c.name("mid")).unwrap().start();
             format!("{}\\{}", &desc[m.start()..pos], &desc[pos..m.end()])
         })
     }
 
+    /// Reverses [`Todo::escape_description`], removing one backslash from every escaped tag
+    pub fn unescape_description(desc: &str) -> Cow<'_, str> {
+        static UNESCAPE_REG: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"(^|\s)(?:(?<pre>\\+)[@+]|\w+(?<mid>\\+):)\S").unwrap());
+        UNESCAPE_REG.replace_all(desc, |c: &Captures| {
+            let m = c.get(0).unwrap();
+            let pos = c.name("pre").or(c.name("mid")).unwrap().start();
+            format!("{}{}", &desc[m.start()..pos], &desc[pos + 1..m.end()])
+        })
+    }
+
     pub async fn read_file(f: impl AsyncRead + Unpin) -> AppResult<Vec<Self>> {
         let mut vec = Vec::new();
         let mut lines = BufReader::new(f).lines();
+        let mut n = 0;
         while let Some(line) = lines.next_line().await.map_err(Error::from)? {
+            n += 1;
             if !line.trim().is_empty() {
-                vec.push(line.parse()?);
+                vec.push(line.parse().map_err(|e: ParseError| e.at_line(n))?);
             }
         }
         Ok(vec)
@@ -145,36 +349,43 @@ impl Display for Todo {
 }
 
 impl FromStr for Todo {
-    type Err = Error;
+    type Err = ParseError;
 
-    fn from_str(mut s: &str) -> AppResult<Self> {
+    /// Parses a line of a todo file. Whitespace between its parts isn't kept, so that the
+    /// description can't start with something that would be parsed as another part once written
+    fn from_str(line: &str) -> Result<Self, ParseError> {
+        let mut s = line.trim_start();
         let mut done: bool = false;
         let mut priority: Option<char> = None;
         let mut created: Option<Date> = None;
         let mut completed: Option<Date> = None;
 
-        if s.starts_with("x ") {
+        if let Some(rest) = s.strip_prefix("x ") {
             done = true;
-            s = &s[2..];
+            s = rest.trim_start();
         }
 
         static PRI_REG: LazyLock<Regex> =
-            LazyLock::new(|| Regex::new(r"^\(?<pri>([A-Z])\)\s+").unwrap());
+            LazyLock::new(|| Regex::new(r"^\((?<pri>[A-Z])\)\s+").unwrap());
         if let Some(pri) = PRI_REG.captures(s) {
             s = &s[pri.get(0).unwrap().len()..];
-            priority = Some(pri.name("pri").unwrap().as_str().chars().next().unwrap());
+            priority = pri.name("pri").and_then(|p| p.as_str().chars().next());
         }
 
         if let Some((part, rest)) = s.split_once(char::is_whitespace) {
+            let rest = rest.trim_start();
             if let Ok(date) = Date::from_str(part) {
                 if let Some((creat, rest)) = rest
                     .split_once(char::is_whitespace)
                     .and_then(|(part, rest)| Date::from_str(part).ok().map(|d| (d, rest)))
                 {
                     if !done {
-                        return Err(Error::msg("Completion date present on uncompleted todo"));
+                        return Err(
+                            ParseError::new("Completion date present on uncompleted todo")
+                                .at_offset(line, line.len() - s.len()),
+                        );
                     }
-                    s = rest;
+                    s = rest.trim_start();
                     created = Some(creat);
                     completed = Some(date);
                 } else {
@@ -184,13 +395,7 @@ impl FromStr for Todo {
             }
         }
 
-        Self::new(
-            done,
-            priority,
-            created,
-            completed,
-            s.trim_start().to_string(),
-        )
+        Self::new(done, priority, created, completed, s.to_string())
     }
 }
 
@@ -201,32 +406,223 @@ impl Display for Date {
 }
 
 impl FromStr for Date {
-    type Err = Error;
+    type Err = ParseError;
 
-    fn from_str(s: &str) -> AppResult<Self> {
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let err = || ParseError::new(format!("Invalid date format '{s}', expected YYYY-MM-DD"));
         if let Some((year, rest)) = s.split_once('-') {
             if let Some((month, day)) = rest.split_once('-') {
-                return Ok(Self {
-                    year: year.parse()?,
-                    month: month.parse()?,
-                    day: day.parse()?,
-                });
+                return Self::new(
+                    year.parse().map_err(|_| err())?,
+                    month.parse().map_err(|_| err())?,
+                    day.parse().map_err(|_| err())?,
+                );
+            }
+        }
+        Err(err())
+    }
+}
+
+impl Date {
+    pub fn new(year: u16, month: u8, day: u8) -> Result<Self, ParseError> {
+        if !(1..=12).contains(&month) || day == 0 || day > Self::days_in_month(year, month) {
+            return Err(ParseError::new(format!(
+                "Invalid date {year:04}-{month:02}-{day:02}"
+            )));
+        }
+        Ok(Self { year, month, day })
+    }
+
+    /// Current date, in UTC
+    pub fn today() -> Self {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self::from_days((secs / 86400) as i64)
+    }
+
+    fn days_in_month(year: u16, month: u8) -> u8 {
+        match month {
+            2 if year.is_multiple_of(4)
+                && (!year.is_multiple_of(100) || year.is_multiple_of(400)) =>
+            {
+                29
+            }
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+    /// Number of days since 1970-01-01
+    pub fn days(&self) -> i64 {
+        // See http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+        let (m, d) = (self.month as i64, self.day as i64);
+        let y = self.year as i64 - if m <= 2 { 1 } else { 0 };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    /// Inverse of [`Date::days`]
+    pub fn from_days(days: i64) -> Self {
+        // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        Self {
+            year: year.clamp(0, u16::MAX as i64) as u16,
+            month: month as u8,
+            day: day as u8,
+        }
+    }
+
+    /// Day of the week, from 0 for monday to 6 for sunday
+    pub fn weekday(&self) -> u8 {
+        (self.days() + 3).rem_euclid(7) as u8
+    }
+
+    pub fn add_days(&self, days: i64) -> Self {
+        Self::from_days(self.days() + days)
+    }
+
+    /// Adds months to the date, clamping the day to the length of the resulting month
+    pub fn add_months(&self, months: i64) -> Self {
+        let total = self.year as i64 * 12 + self.month as i64 - 1 + months;
+        let year = total.div_euclid(12).clamp(0, u16::MAX as i64) as u16;
+        let month = total.rem_euclid(12) as u8 + 1;
+        Self {
+            year,
+            month,
+            day: self.day.min(Self::days_in_month(year, month)),
+        }
+    }
+
+    /// Parses either a date, or a [`Period`] after this date such as `3d` or `1w`
+    pub fn parse_after(&self, s: &str) -> Result<Date, ParseError> {
+        s.parse().or_else(|_| match s.parse::<Period>() {
+            Ok(period) => Ok(period.add_to(self)),
+            Err(_) => Err(ParseError::new(format!(
+                "Invalid date or period '{s}', expected e.g. 2024-03-01, 3d, 2b or 1w"
+            ))),
+        })
+    }
+}
+
+impl Period {
+    pub fn add_to(&self, date: &Date) -> Date {
+        let count = self.count as i64;
+        match self.unit {
+            PeriodUnit::Days => date.add_days(count),
+            PeriodUnit::BusinessDays if count == 0 => *date,
+            PeriodUnit::BusinessDays => {
+                // Every 5 business days are a week, except for the last ones which end on a
+                // weekday even when starting on a weekend
+                let weeks = (count - 1) / 5;
+                let mut date = date.add_days(weeks * 7);
+                for _ in 0..count - weeks * 5 {
+                    date = date.add_days(1);
+                    while date.weekday() >= 5 {
+                        date = date.add_days(1);
+                    }
+                }
+                date
             }
+            PeriodUnit::Weeks => date.add_days(count * 7),
+            PeriodUnit::Months => date.add_months(count),
+            PeriodUnit::Years => date.add_months(count * 12),
+        }
+    }
+}
+
+impl FromStr for Period {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let err = || {
+            ParseError::new(format!(
+                "Invalid period '{s}', expected e.g. 3d, 2b, 1w, 1m or 1y"
+            ))
+        };
+        let (count, unit) = s.split_at(s.len() - s.chars().last().ok_or_else(err)?.len_utf8());
+        let count = if count.is_empty() {
+            1
+        } else {
+            count.parse().map_err(|_| err())?
+        };
+        if count == 0 {
+            return Err(err());
         }
-        Err(Error::msg("Invalid date format"))
+        let unit = match unit {
+            "d" => PeriodUnit::Days,
+            "b" => PeriodUnit::BusinessDays,
+            "w" => PeriodUnit::Weeks,
+            "m" => PeriodUnit::Months,
+            "y" => PeriodUnit::Years,
+            _ => return Err(err()),
+        };
+        Ok(Self { count, unit })
+    }
+}
+
+impl Display for Period {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let unit = match self.unit {
+            PeriodUnit::Days => 'd',
+            PeriodUnit::BusinessDays => 'b',
+            PeriodUnit::Weeks => 'w',
+            PeriodUnit::Months => 'm',
+            PeriodUnit::Years => 'y',
+        };
+        write!(f, "{}{}", self.count, unit)
+    }
+}
+
+impl FromStr for Recurrence {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let (strict, period) = match s.strip_prefix('+') {
+            Some(period) => (true, period),
+            None => (false, s),
+        };
+        Ok(Self {
+            strict,
+            period: period.parse()?,
+        })
+    }
+}
+
+impl Display for Recurrence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.strict {
+            f.write_str("+")?;
+        }
+        write!(f, "{}", self.period)
     }
 }
 
 impl<'a> DescriptionPart<'a> {
-    fn parse(s: &'a str) -> AppResult<Self> {
-        if s.starts_with('@') {
-            Ok(DescriptionPart::Context(&s[1..]))
-        } else if s.starts_with('+') {
-            Ok(DescriptionPart::Project(&s[1..]))
+    pub fn parse(s: &'a str) -> Result<Self, ParseError> {
+        if let Some(ctx) = s.strip_prefix('@') {
+            Ok(DescriptionPart::Context(ctx))
+        } else if let Some(prj) = s.strip_prefix('+') {
+            Ok(DescriptionPart::Project(prj))
         } else if let Some((k, v)) = s.split_once(':') {
             Ok(DescriptionPart::Data(k, v))
         } else {
-            Err(Error::msg(format!("Couldn't parse DescriptionPart '{s}'")))
+            Err(ParseError::new(format!(
+                "Couldn't parse DescriptionPart '{s}'"
+            )))
         }
     }
 }
@@ -264,3 +660,231 @@ where
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_tag_parse() {
+        fn meta(desc: &str) -> Vec<String> {
+            Todo::new(false, None, None, None, desc.into())
+                .unwrap()
+                .find_meta()
+                .map(|p| p.to_string())
+                .collect()
+        }
+        assert_eq!(
+            meta("Review +foo. and @bar, see http://x/"),
+            vec!["+foo.", "@bar,", "http://x/"]
+        );
+        assert_eq!(meta("mail user@example.com at 10:30"), vec!["10:30"]);
+        assert_eq!(meta("a+b c:d:e"), vec!["c:d:e"]);
+        assert_eq!(meta("+ @ key: :value"), Vec::<String>::new());
+        assert_eq!(
+            meta("é:日本 +プロジェクト"),
+            vec!["é:日本", "+プロジェクト"]
+        );
+    }
+
+    #[test]
+    fn test_escape_cases() {
+        for (raw, escaped) in [
+            ("see http://x/", "see http\\://x/"),
+            (
+                "https://gitlab.example/a/b?c=d#e",
+                "https\\://gitlab.example/a/b?c=d#e",
+            ),
+            ("mail user@example.com", "mail user@example.com"),
+            ("mailto:user@example.com", "mailto\\:user@example.com"),
+            ("meet at 10:30.", "meet at 10\\:30."),
+            ("+foo. @bar,", "\\+foo. \\@bar,"),
+            (
+                "already \\+escaped key\\:val",
+                "already \\\\+escaped key\\\\:val",
+            ),
+            ("日本:語 +é", "日本\\:語 \\+é"),
+            ("no meta + @ : a+b", "no meta + @ : a+b"),
+            ("line\n+next", "line\n\\+next"),
+        ] {
+            assert_eq!(Todo::escape_description(raw), escaped, "Escaping {raw:?}");
+            assert_eq!(
+                Todo::unescape_description(escaped),
+                raw,
+                "Unescaping {escaped:?}"
+            );
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_escape_roundtrip(s in r"([a-z0-9é日@+:\\./ \t\n]|http://){0,40}") {
+            let escaped = Todo::escape_description(&s);
+            prop_assert_eq!(Todo::unescape_description(&escaped), s.as_str());
+            let todo = Todo::new(false, None, None, None, escaped.to_string()).unwrap();
+            prop_assert_eq!(todo.find_meta().collect::<Vec<_>>(), vec![]);
+        }
+    }
+
+    #[test]
+    fn test_tag_escape() {
+        const PRJ: &str = "testprj";
+        const CTX: &str = "testctx";
+        const DATAK: &str = "test";
+        const DATAV: &str = "data";
+        let todo = Todo::new(false, None, None, None, "Test".into()).unwrap()
+            + DescriptionPart::Project(PRJ)
+            + DescriptionPart::Context(CTX)
+            + DescriptionPart::Data(DATAK, DATAV);
+        let escaped = Todo::new(
+            false,
+            None,
+            None,
+            None,
+            Todo::escape_description(&todo.description).to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            escaped.find_meta().collect::<Vec<_>>(),
+            vec![],
+            "Escaped description shouldn't return any meta"
+        );
+        for s in [PRJ, CTX, DATAK, DATAV] {
+            assert!(
+                escaped.description.find(s).is_some(),
+                "Escaped description should still contain '{}'",
+                s
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parse_error_location() {
+        let file = "First\n\n2024-01-02 2024-01-01 Not done\n";
+        let Err(Error::Parse(e)) = Todo::read_file(file.as_bytes()).await else {
+            panic!("Reading an invalid file should return a ParseError");
+        };
+        assert_eq!((e.line, e.column), (Some(3), Some(1)));
+        assert_eq!(
+            e.to_string(),
+            "line 3, column 1: Completion date present on uncompleted todo"
+        );
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let todo: Todo = "x (B) 2024-01-02 2024-01-01 Call mom +family"
+            .parse()
+            .unwrap();
+        assert!(todo.done);
+        assert_eq!(todo.priority, Some('B'));
+        assert_eq!(todo.completed, Some("2024-01-02".parse().unwrap()));
+        assert_eq!(todo.created, Some("2024-01-01".parse().unwrap()));
+        assert_eq!(todo.description, "Call mom +family");
+
+        // Found by fuzzing: the first used to panic, the others not to be written back as is
+        for (line, written) in [
+            ("(<pri>A) x", "(<pri>A) x"),
+            ("\nx ", "x "),
+            (" 2024-01-01 x", "2024-01-01 x"),
+            ("(A)  2024-01-01  Spaced", "(A) 2024-01-01 Spaced"),
+        ] {
+            let todo: Todo = line.parse().unwrap();
+            assert_eq!(todo.to_string(), written, "Parsing {line:?}");
+            assert_eq!(
+                written.parse::<Todo>().unwrap(),
+                todo,
+                "Parsing {written:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_dates() {
+        let date = |s: &str| s.parse::<Date>().unwrap();
+        assert!("2024-02-30".parse::<Date>().is_err());
+        assert!("2024-13-01".parse::<Date>().is_err());
+        assert_eq!(date("1970-01-01").days(), 0);
+        for d in ["1969-12-31", "2000-02-29", "2024-12-31", "2100-03-01"] {
+            assert_eq!(Date::from_days(date(d).days()), date(d));
+        }
+        assert_eq!(date("2024-01-01").weekday(), 0);
+        assert_eq!(date("2024-01-31").add_months(1), date("2024-02-29"));
+        assert_eq!(date("2024-12-30").add_days(3), date("2025-01-02"));
+
+        let period = |s: &str| s.parse::<Period>().unwrap();
+        assert_eq!(period("2b").add_to(&date("2024-01-05")), date("2024-01-09"));
+        assert_eq!(period("w").add_to(&date("2024-01-05")), date("2024-01-12"));
+        assert_eq!(period("1y").add_to(&date("2024-02-29")), date("2025-02-28"));
+        for (start, end) in [("2024-01-06", "2024-01-12"), ("2024-01-03", "2024-01-10")] {
+            assert_eq!(period("5b").add_to(&date(start)), date(end));
+        }
+        assert_eq!(
+            period("11b").add_to(&date("2024-01-05")),
+            date("2024-01-22")
+        );
+        assert!(period("4000000000b").add_to(&date("2024-01-05")) > date("2024-01-05"));
+        assert!("1x".parse::<Period>().is_err());
+        assert!("0d".parse::<Period>().is_err());
+        assert!("".parse::<Period>().is_err());
+
+        let today = date("2024-01-05");
+        assert_eq!(today.parse_after("1w").unwrap(), date("2024-01-12"));
+        assert_eq!(today.parse_after("2024-03-01").unwrap(), date("2024-03-01"));
+        assert!(today.parse_after("next week").is_err());
+        let error = today.parse_after("-3d").unwrap_err();
+        assert!(error.message.contains("date or period"), "{error}");
+    }
+
+    #[test]
+    fn test_extension_tags() {
+        let todo: Todo = "(A) 2024-01-01 Task due:2024-02-01 t:2024-01-15 rec:+2w h:1 pri:B"
+            .parse()
+            .unwrap();
+        assert!(todo.validate().is_ok());
+        assert_eq!(todo.due().unwrap(), Some("2024-02-01".parse().unwrap()));
+        assert_eq!(
+            todo.threshold().unwrap(),
+            Some("2024-01-15".parse().unwrap())
+        );
+        assert_eq!(
+            todo.recurrence().unwrap(),
+            Some(Recurrence {
+                strict: true,
+                period: Period {
+                    count: 2,
+                    unit: PeriodUnit::Weeks
+                }
+            })
+        );
+        assert!(todo.is_hidden());
+        assert_eq!(todo.priority_tag().unwrap(), Some('B'));
+
+        for invalid in ["due:tomorrow", "t:2024-02-30", "rec:2q", "pri:b", "h:yes"] {
+            let todo = Todo::new(false, None, None, None, format!("Task {invalid}")).unwrap();
+            assert!(todo.validate().is_err(), "{invalid} should be invalid");
+        }
+
+        let mut todo = Todo::new(false, None, None, None, "t:2024-01-01 Task h:1".into()).unwrap();
+        todo.set_data("t", "2024-03-01");
+        todo.set_hidden(false);
+        todo.set_data("due", "2024-04-01");
+        assert_eq!(todo.description, "t:2024-03-01 Task due:2024-04-01");
+        assert!(todo.remove_data("t"));
+        assert!(!todo.remove_data("t"));
+        assert_eq!(todo.description, "Task due:2024-04-01");
+
+        let mut todo = Todo::new(
+            false,
+            None,
+            None,
+            None,
+            "@gitlab Task @gitlabs @gitlab".into(),
+        )
+        .unwrap();
+        assert!(todo.remove_tag(DescriptionPart::Context("gitlab")));
+        assert!(!todo.remove_tag(DescriptionPart::Context("gitlab")));
+        assert_eq!(todo.description, "Task @gitlabs");
+    }
+}
@@ -0,0 +1,365 @@
+use crate::error::AppResult;
+use crate::gitlab::GitlabTodo;
+use crate::sync::{SyncEngine, SyncOutcome};
+use crate::todo::{Date, DescriptionPart, Todo, THRESHOLD_TAG};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEventKind};
+use futures::StreamExt;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::{BTreeSet, HashMap};
+use url::Url;
+
+const HELP: &str = "j/k: move, x: done, s/S: snooze 1/7 days, 1-5: priority, 0: no priority, \
+    o: open, p/a: filter by project/action, r: sync, q: quit";
+
+/// A pending synced todo listed by the triage UI
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub id: usize,
+    pub todo: Todo,
+    /// First project tag of the todo, which the sync sets to its project or group
+    pub project: Option<String>,
+    /// Action of the todo on Gitlab, e.g. "review_requested", if it's still pending there
+    pub action: Option<String>,
+    /// Page of the target of the todo, if it's still pending on Gitlab
+    pub url: Option<Url>,
+}
+
+/// A change to a todo
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edit {
+    /// Complete it, which marks it as done on Gitlab
+    Done,
+    /// Hide it for some days, with a threshold date
+    Snooze(i64),
+    Priority(Option<char>),
+}
+
+/// State of the triage UI, independently of the terminal: the listed todos, the filters and the
+/// selection
+#[derive(Debug, Clone, Default)]
+pub struct Triage {
+    pub items: Vec<Item>,
+    /// Only list the todos of this project
+    pub project: Option<String>,
+    /// Only list the todos with this action
+    pub action: Option<String>,
+    /// Index of the selected item among the visible ones
+    pub selected: usize,
+}
+
+impl Item {
+    /// Applies an edit to the todo, as of `today`
+    pub fn edit(&self, edit: Edit, today: &Date) -> Todo {
+        let mut todo = self.todo.clone();
+        match edit {
+            Edit::Done => {
+                todo.done = true;
+                todo.created.get_or_insert(*today);
+                todo.completed = Some(*today);
+            }
+            Edit::Snooze(days) => {
+                todo.set_data(THRESHOLD_TAG, today.add_days(days).to_string());
+            }
+            Edit::Priority(priority) => todo.priority = priority,
+        }
+        todo
+    }
+}
+
+impl Triage {
+    /// Lists the pending `todos` of the files, with what Gitlab says of them in `live`, the
+    /// todos fetched from Gitlab
+    pub fn new(todos: Vec<Todo>, live: &[GitlabTodo]) -> Self {
+        let live: HashMap<usize, &GitlabTodo> = live.iter().map(|t| (t.id, t)).collect();
+        let items = todos
+            .into_iter()
+            .filter(|t| !t.done)
+            .filter_map(|todo| {
+                let id = todo.get_data("id")?.parse().ok()?;
+                let gitlab = live.get(&id);
+                let project = todo.find_meta().find_map(|m| match m {
+                    DescriptionPart::Project(p) => Some(p.to_string()),
+                    _ => None,
+                });
+                Some(Item {
+                    id,
+                    project,
+                    action: gitlab.map(|t| t.action_name.clone()),
+                    url: gitlab.map(|t| t.target_url.clone()),
+                    todo,
+                })
+            })
+            .collect();
+        Self {
+            items,
+            ..Default::default()
+        }
+    }
+
+    /// Keeps the filters and the selected todo of `previous` when possible, e.g. after a sync
+    pub fn keeping(mut self, previous: &Triage) -> Self {
+        self.project.clone_from(&previous.project);
+        self.action.clone_from(&previous.action);
+        let selected = previous.selected_item().map(|i| i.id);
+        let visible = self.visible();
+        self.selected = match visible.iter().position(|i| Some(i.id) == selected) {
+            Some(i) => i,
+            None => previous.selected.min(visible.len().saturating_sub(1)),
+        };
+        self
+    }
+
+    /// The items matching the filters
+    pub fn visible(&self) -> Vec<&Item> {
+        self.items
+            .iter()
+            .filter(|i| self.project.is_none() || i.project == self.project)
+            .filter(|i| self.action.is_none() || i.action == self.action)
+            .collect()
+    }
+
+    pub fn selected_item(&self) -> Option<&Item> {
+        self.visible().get(self.selected).copied()
+    }
+
+    /// Moves the selection by `offset` items, staying within the visible ones
+    pub fn select(&mut self, offset: isize) {
+        let last = self.visible().len().saturating_sub(1);
+        self.selected = self.selected.saturating_add_signed(offset).min(last);
+    }
+
+    /// Filters by the next project, in alphabetical order, then by none
+    pub fn cycle_project(&mut self) {
+        let projects = self
+            .items
+            .iter()
+            .filter_map(|i| i.project.clone())
+            .collect();
+        self.project = next(projects, self.project.take());
+        self.selected = 0;
+    }
+
+    /// Filters by the next action, in alphabetical order, then by none
+    pub fn cycle_action(&mut self) {
+        let actions = self.items.iter().filter_map(|i| i.action.clone()).collect();
+        self.action = next(actions, self.action.take());
+        self.selected = 0;
+    }
+
+    /// Title of the list, with the filters
+    fn title(&self) -> String {
+        let mut title = format!(" {} todos ", self.visible().len());
+        for filter in [&self.project, &self.action].into_iter().flatten() {
+            title += &format!("[{filter}] ");
+        }
+        title
+    }
+}
+
+/// The value after `current` in `values`, or the first one if none, or none after the last one
+fn next(values: BTreeSet<String>, current: Option<String>) -> Option<String> {
+    match current {
+        Some(current) => values.into_iter().find(|v| *v > current),
+        None => values.into_iter().next(),
+    }
+}
+
+/// Runs the triage UI in the terminal until the user quits. It starts with a sync, and each
+/// change is written to the files and synced right away, which pushes completed todos to Gitlab
+pub async fn run(engine: &SyncEngine) -> AppResult<()> {
+    let mut terminal = ratatui::init();
+    let result = triage(engine, &mut terminal).await;
+    ratatui::restore();
+    result
+}
+
+async fn triage(engine: &SyncEngine, terminal: &mut DefaultTerminal) -> AppResult<()> {
+    let mut message = "Syncing...".to_string();
+    let mut state = Triage::default();
+    terminal.draw(|frame| draw(frame, &state, &message))?;
+    state = load(engine, engine.run().await?).await?;
+    message = HELP.into();
+
+    let mut events = EventStream::new();
+    while let Some(event) = events.next().await {
+        let Event::Key(key) = event? else {
+            terminal.draw(|frame| draw(frame, &state, &message))?;
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let edit = match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => break,
+            KeyCode::Char('j') | KeyCode::Down => {
+                state.select(1);
+                None
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                state.select(-1);
+                None
+            }
+            KeyCode::Char('p') => {
+                state.cycle_project();
+                None
+            }
+            KeyCode::Char('a') => {
+                state.cycle_action();
+                None
+            }
+            KeyCode::Char('o') | KeyCode::Enter => {
+                message = match state.selected_item().and_then(|i| i.url.as_ref()) {
+                    Some(url) => match webbrowser::open(url.as_str()) {
+                        Ok(()) => format!("Opened {url}"),
+                        Err(e) => format!("Couldn't open {url}: {e}"),
+                    },
+                    None => "This todo isn't pending on Gitlab anymore".into(),
+                };
+                None
+            }
+            KeyCode::Char('x') => Some(Edit::Done),
+            KeyCode::Char('s') => Some(Edit::Snooze(1)),
+            KeyCode::Char('S') => Some(Edit::Snooze(7)),
+            KeyCode::Char('0') => Some(Edit::Priority(None)),
+            KeyCode::Char(c @ '1'..='5') => Some(Edit::Priority(char::from_u32(
+                'A' as u32 + c as u32 - '1' as u32,
+            ))),
+            KeyCode::Char('r') => {
+                message = "Syncing...".into();
+                terminal.draw(|frame| draw(frame, &state, &message))?;
+                message = sync(engine, &mut state, &[]).await;
+                None
+            }
+            _ => None,
+        };
+        if let (Some(edit), Some(item)) = (edit, state.selected_item()) {
+            let todo = item.edit(edit, &Date::today());
+            message = format!("Saving #{}...", item.id);
+            terminal.draw(|frame| draw(frame, &state, &message))?;
+            message = sync(engine, &mut state, &[todo]).await;
+        }
+        terminal.draw(|frame| draw(frame, &state, &message))?;
+    }
+    Ok(())
+}
+
+/// Writes the `edited` todos and syncs, returning the message to show. Failures are shown rather
+/// than returned, leaving the files as they were
+async fn sync(engine: &SyncEngine, state: &mut Triage, edited: &[Todo]) -> String {
+    let result = async { load(engine, engine.edit_todos(edited).await?).await }.await;
+    match result {
+        Ok(new) => {
+            *state = new.keeping(state);
+            HELP.into()
+        }
+        Err(e) => format!("Sync failed: {e}"),
+    }
+}
+
+/// Lists the todos of the files after a sync, with the todos it fetched
+async fn load(engine: &SyncEngine, outcome: SyncOutcome) -> AppResult<Triage> {
+    let mut todos = Vec::new();
+    for file in outcome.files {
+        let existing = Todo::read_file(&file.content[..]).await?;
+        todos.extend(existing.into_iter().filter(|t| engine.is_synced(t)));
+    }
+    Ok(Triage::new(todos, &outcome.fetched))
+}
+
+fn draw(frame: &mut Frame, state: &Triage, message: &str) {
+    let [list_area, message_area] =
+        Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+    let items: Vec<ListItem> = state
+        .visible()
+        .into_iter()
+        .map(|i| {
+            let todo = &i.todo;
+            let priority = todo.priority.map(|p| format!("({p}) ")).unwrap_or_default();
+            ListItem::new(format!("{priority}{}", todo.description))
+        })
+        .collect();
+    let list = List::new(items)
+        .block(Block::bordered().title(state.title()))
+        .highlight_style(Style::new().reversed());
+    let mut list_state = ListState::default().with_selected(Some(state.selected));
+    frame.render_stateful_widget(list, list_area, &mut list_state);
+    frame.render_widget(Paragraph::new(message).dim(), message_area);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn gitlab_todo(id: usize, action: &str) -> GitlabTodo {
+        serde_json::from_value(json!({
+            "id": id,
+            "body": "Body",
+            "state": "pending",
+            "created_at": "2024-01-01T00:00:00Z",
+            "updated_at": "2024-01-01T00:00:00Z",
+            "action_name": action,
+            "target_type": "MergeRequest",
+            "target_url": format!("https://git.example/{id}"),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_triage() {
+        let todos = [
+            "2024-01-01 Review +main/app id:1 @gitlab",
+            "2024-01-01 Fix +infra/db id:2 @gitlab",
+            "x 2024-01-02 2024-01-01 Done +main/app id:3 @gitlab",
+            "2024-01-01 Deleted +main/app id:4 @gitlab",
+        ];
+        let todos = todos.iter().map(|t| t.parse().unwrap()).collect();
+        let live = [
+            gitlab_todo(1, "review_requested"),
+            gitlab_todo(2, "assigned"),
+        ];
+        let mut state = Triage::new(todos, &live);
+        let ids = |state: &Triage| -> Vec<usize> { state.visible().iter().map(|i| i.id).collect() };
+        assert_eq!(ids(&state), [1, 2, 4]);
+        assert_eq!(state.items[2].url, None);
+
+        state.select(5);
+        assert_eq!(state.selected_item().unwrap().id, 4);
+        state.select(-1);
+        assert_eq!(state.selected_item().unwrap().id, 2);
+        let refreshed = Triage::new(vec![state.items[1].todo.clone()], &live).keeping(&state);
+        assert_eq!(refreshed.selected_item().unwrap().id, 2);
+
+        state.cycle_project();
+        assert_eq!(state.project.as_deref(), Some("infra/db"));
+        assert_eq!(ids(&state), [2]);
+        state.cycle_project();
+        assert_eq!(ids(&state), [1, 4]);
+        state.cycle_project();
+        assert_eq!(state.project, None);
+        state.cycle_action();
+        state.cycle_action();
+        assert_eq!(state.action.as_deref(), Some("review_requested"));
+        assert_eq!(ids(&state), [1]);
+    }
+
+    #[test]
+    fn test_edit() {
+        let item =
+            Triage::new(vec!["2024-01-01 Review id:1".parse().unwrap()], &[]).items[0].clone();
+        let today = "2024-03-01".parse().unwrap();
+        let edited = |edit| item.edit(edit, &today).to_string();
+        assert_eq!(edited(Edit::Done), "x 2024-03-01 2024-01-01 Review id:1");
+        assert_eq!(
+            edited(Edit::Snooze(7)),
+            "2024-01-01 Review id:1 t:2024-03-08"
+        );
+        assert_eq!(
+            edited(Edit::Priority(Some('B'))),
+            "(B) 2024-01-01 Review id:1"
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use crate::config::SecretString;
+use crate::error::{AppResult, IoError};
+use crate::sync::Scope;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use axum::Router;
+use documented::DocumentedFields;
+use log::*;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Path the webhooks must be sent to
+pub const WEBHOOK_PATH: &str = "/webhook";
+
+/// Types of the notes whose todos can be refreshed
+const NOTEABLE_TYPES: [&str; 3] = ["MergeRequest", "Issue", "Commit"];
+
+/// Settings of the webhook receiver, see [`AppConfig::webhook`]
+///
+/// [`AppConfig::webhook`]: crate::config::AppConfig::webhook
+#[derive(Deserialize, Clone, Debug, DocumentedFields)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Address to listen on, e.g. "127.0.0.1:8765"
+    pub listen: SocketAddr,
+    /// Secret token of the webhook in Gitlab, which it sends in the X-Gitlab-Token header
+    pub secret: SecretString,
+}
+
+/// HTTP server receiving Gitlab webhooks, and reporting the todos they may affect
+pub struct WebhookServer {
+    listener: TcpListener,
+    secret: SecretString,
+}
+
+struct ServerState {
+    secret: SecretString,
+    scopes: UnboundedSender<Scope>,
+}
+
+/// The parts of a Merge Request, Issue or Note event used to find the affected todos. System
+/// hooks send the same payloads
+#[derive(Deserialize)]
+struct Event {
+    object_kind: String,
+    /// Parsed once the event is known to be relevant, as the other ones may not have a project
+    project: Option<serde_json::Value>,
+    #[serde(default)]
+    object_attributes: Option<ObjectAttributes>,
+}
+
+#[derive(Deserialize)]
+struct Project {
+    id: u64,
+    path_with_namespace: String,
+}
+
+#[derive(Deserialize)]
+struct ObjectAttributes {
+    noteable_type: Option<String>,
+}
+
+impl WebhookServer {
+    pub async fn bind(config: &WebhookConfig) -> AppResult<Self> {
+        let listener = TcpListener::bind(config.listen)
+            .await
+            .map_err(|e| IoError {
+                path: None,
+                source: std::io::Error::new(e.kind(), format!("{}: {e}", config.listen)),
+            })?;
+        Ok(Self {
+            listener,
+            secret: config.secret.clone(),
+        })
+    }
+
+    pub fn local_addr(&self) -> AppResult<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serves until the process exits, sending the scope of the relevant events to `scopes`
+    pub async fn serve(self, scopes: UnboundedSender<Scope>) -> AppResult<()> {
+        let state = Arc::new(ServerState {
+            secret: self.secret,
+            scopes,
+        });
+        let app = Router::new()
+            .route(WEBHOOK_PATH, post(receive))
+            .with_state(state);
+        info!(
+            "Listening for webhooks on http://{}{WEBHOOK_PATH}",
+            self.listener.local_addr()?
+        );
+        Ok(axum::serve(self.listener, app).await?)
+    }
+}
+
+async fn receive(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let token = headers
+        .get("x-gitlab-token")
+        .map(|t| t.as_bytes())
+        .unwrap_or_default();
+    if !constant_time_eq(token, state.secret.as_ref().as_bytes()) {
+        warn!("Rejected a webhook with an invalid token");
+        return StatusCode::UNAUTHORIZED;
+    }
+    match serde_json::from_slice::<Event>(&body) {
+        Ok(event) => match scope_of(event) {
+            Some(scope) => {
+                debug!("Webhook for {scope:?}");
+                let _ = state.scopes.send(scope);
+                StatusCode::ACCEPTED
+            }
+            None => StatusCode::NO_CONTENT,
+        },
+        Err(e) => {
+            warn!("Invalid webhook payload: {e}");
+            StatusCode::BAD_REQUEST
+        }
+    }
+}
+
+/// The todos an event may have created, updated or resolved: those of its project, with the
+/// type of its target
+fn scope_of(event: Event) -> Option<Scope> {
+    let target_type = match event.object_kind.as_str() {
+        "merge_request" => "MergeRequest".to_string(),
+        "issue" | "work_item" => "Issue".to_string(),
+        // Snippets can't have todos, so the todos API rejects them as a type
+        "note" => Some(event.object_attributes?.noteable_type?)
+            .filter(|t| NOTEABLE_TYPES.contains(&t.as_str()))?,
+        _ => return None,
+    };
+    let project: Project = serde_json::from_value(event.project?).ok()?;
+    Some(Scope {
+        project_id: project.id,
+        project_path: project.path_with_namespace,
+        target_type,
+    })
+}
+
+/// Compares secrets without leaking their common prefix length through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_receive() {
+        let config = WebhookConfig {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            secret: SecretString("s3cret".into()),
+        };
+        let server = WebhookServer::bind(&config).await.unwrap();
+        let url = format!("http://{}{WEBHOOK_PATH}", server.local_addr().unwrap());
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(server.serve(tx));
+
+        let client = reqwest::Client::new();
+        let send = |token: &'static str, body: serde_json::Value| {
+            client
+                .post(&url)
+                .header("X-Gitlab-Token", token)
+                .json(&body)
+                .send()
+        };
+        let note = serde_json::json!({
+            "object_kind": "note",
+            "project": {"id": 12, "path_with_namespace": "main/app"},
+            "object_attributes": {"noteable_type": "MergeRequest", "note": "LGTM"},
+        });
+        let push = serde_json::json!({"object_kind": "push", "project": {"id": 12}});
+        let mut snippet_note = note.clone();
+        snippet_note["object_attributes"]["noteable_type"] = "Snippet".into();
+
+        let status = |r: reqwest::Result<reqwest::Response>| r.unwrap().status().as_u16();
+        assert_eq!(status(send("wrong", note.clone()).await), 401);
+        assert_eq!(status(send("s3cret", push).await), 204);
+        assert_eq!(status(send("s3cret", snippet_note).await), 204);
+        assert_eq!(status(send("s3cret", note).await), 202);
+        assert_eq!(
+            rx.recv().await,
+            Some(Scope {
+                project_id: 12,
+                project_path: "main/app".into(),
+                target_type: "MergeRequest".into(),
+            })
+        );
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,367 @@
+//! An in-process fake Gitlab serving the parts of the REST API used by the sync, recording the
+//! requests it receives, and failing some of them on demand
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use gitlab_todotxt_sync::config::{AppConfig, SecretString};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use url::Url;
+
+/// Token accepted by the mock, see [`MockGitlab::config`]
+pub const TOKEN: &str = "glpat-mock-token";
+pub const USER_ID: u64 = 1;
+pub const USERNAME: &str = "jdoe";
+
+pub struct MockGitlab {
+    pub url: Url,
+    state: Arc<Mutex<MockState>>,
+}
+
+/// A request received by the mock
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub method: Method,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub token: Option<String>,
+}
+
+#[derive(Default)]
+struct MockState {
+    /// Todos as returned by the API
+    todos: Vec<Value>,
+    /// Projects and groups by id, with their full path
+    projects: Vec<(u64, String)>,
+    groups: Vec<(u64, String)>,
+    /// Number of items per page, overriding the requested one if lower
+    page_size: Option<usize>,
+    /// Statuses to answer to the next requests whose path starts with the given one
+    failures: VecDeque<(String, StatusCode)>,
+    calls: Vec<Call>,
+}
+
+impl MockGitlab {
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let app = Router::new().fallback(handle).with_state(state.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Self { url, state }
+    }
+
+    /// A config syncing from this mock to `todo.txt` in `dir`, without retry delays
+    pub fn config(&self, dir: &TestDir) -> AppConfig {
+        AppConfig {
+            gitlab_token: SecretString(TOKEN.into()),
+            gitlab_host: self.url.clone(),
+            todo_file: dir.path("todo.txt"),
+            context_tag: Some("gitlab".into()),
+            max_retries: 2,
+            state_file: dir.path("state.json"),
+            ..Default::default()
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn add_todo(&self, todo: Value) {
+        self.state().todos.push(todo);
+    }
+
+    pub fn add_project(&self, id: u64, path: &str) {
+        self.state().projects.push((id, path.into()));
+    }
+
+    pub fn add_group(&self, id: u64, path: &str) {
+        self.state().groups.push((id, path.into()));
+    }
+
+    pub fn set_page_size(&self, size: usize) {
+        self.state().page_size = Some(size);
+    }
+
+    /// Answers `status` to the next request whose path starts with `path`, e.g. "todos". Server
+    /// errors ask to retry right away
+    pub fn fail_next(&self, path: &str, status: u16) {
+        let status = StatusCode::from_u16(status).unwrap();
+        self.state().failures.push_back((path.into(), status));
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.state().calls.clone()
+    }
+
+    /// The calls to an endpoint, e.g. "todos"
+    pub fn calls_to(&self, method: Method, path: &str) -> Vec<Call> {
+        let calls = self.calls().into_iter();
+        calls
+            .filter(|c| c.method == method && c.path == path)
+            .collect()
+    }
+
+    pub fn todo_state(&self, id: u64) -> Option<String> {
+        let state = self.state();
+        let todo = state.todos.iter().find(|t| t["id"] == id)?;
+        todo["state"].as_str().map(str::to_owned)
+    }
+}
+
+/// A pending todo on a merge request of `project`, created on 2024-01-`id`
+pub fn todo(id: u64, project: &str, body: &str) -> Value {
+    json!({
+        "id": id,
+        "body": body,
+        "state": "pending",
+        "created_at": format!("2024-01-{id:02}T10:00:00.000Z"),
+        "updated_at": format!("2024-01-{id:02}T10:00:00.000Z"),
+        "action_name": "review_requested",
+        "target_type": "MergeRequest",
+        "author": {"id": 2, "username": "alice"},
+        "project": {"path_with_namespace": project},
+        "target_url": format!("https://git.example/{project}/-/merge_requests/{id}"),
+    })
+}
+
+/// The same todo, done on 2024-02-01
+pub fn done(mut todo: Value) -> Value {
+    todo["state"] = "done".into();
+    todo["updated_at"] = "2024-02-01T10:00:00.000Z".into();
+    todo
+}
+
+async fn handle(
+    State(state): State<Arc<Mutex<MockState>>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    _body: Bytes,
+) -> Response {
+    let Some(path) = uri.path().strip_prefix("/api/v4/") else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let path = path.trim_end_matches('/');
+    let query: HashMap<String, String> =
+        url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+            .into_owned()
+            .collect();
+    let token = headers
+        .get("private-token")
+        .and_then(|t| t.to_str().ok())
+        .map(str::to_owned);
+    let mut state = state.lock().unwrap();
+    state.calls.push(Call {
+        method: method.clone(),
+        path: path.into(),
+        query: query.clone(),
+        token: token.clone(),
+    });
+
+    if let Some(i) = state.failures.iter().position(|(p, _)| path.starts_with(p)) {
+        let (_, status) = state.failures.remove(i).unwrap();
+        let message = json!({"message": status.canonical_reason()});
+        return (status, [("retry-after", "0")], axum::Json(message)).into_response();
+    }
+    if token.as_deref() != Some(TOKEN) {
+        let message = json!({"message": "401 Unauthorized"});
+        return (StatusCode::UNAUTHORIZED, axum::Json(message)).into_response();
+    }
+
+    let segments: Vec<String> = path.split('/').map(decode).collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    match (&method, segments.as_slice()) {
+        (&Method::GET, ["user"]) => ok(json!({"id": USER_ID, "username": USERNAME})),
+        (&Method::GET, ["users"]) => {
+            // The user of the token, and the authors of the todos
+            let me = json!({"id": USER_ID, "username": USERNAME});
+            let authors = state.todos.iter().map(|t| &t["author"]);
+            let user = [&me].into_iter().chain(authors).find(|u| {
+                query
+                    .get("username")
+                    .is_some_and(|name| u["username"] == *name)
+            });
+            ok(json!(user.into_iter().collect::<Vec<_>>()))
+        }
+        (&Method::GET, ["todos"]) => state.list_todos(&query),
+        (&Method::POST, ["todos", id, "mark_as_done"]) => {
+            let todo = state
+                .todos
+                .iter_mut()
+                .find(|t| t["id"].as_u64() == id.parse().ok());
+            match todo {
+                Some(todo) => {
+                    *todo = done(todo.take());
+                    (StatusCode::CREATED, axum::Json(todo.clone())).into_response()
+                }
+                None => not_found(),
+            }
+        }
+        (&Method::GET, ["projects", id]) => entity(&state.projects, id),
+        (&Method::GET, ["groups", id]) => entity(&state.groups, id),
+        _ => not_found(),
+    }
+}
+
+impl MockState {
+    /// The requested page of the todos matching the query, with the pagination headers of Gitlab
+    fn list_todos(&self, query: &HashMap<String, String>) -> Response {
+        let filters = [
+            ("state", "/state"),
+            ("action", "/action_name"),
+            ("type", "/target_type"),
+            ("author_id", "/author/id"),
+        ];
+        let mut todos: Vec<&Value> = self
+            .todos
+            .iter()
+            .filter(|t| {
+                filters.iter().all(|(param, field)| {
+                    query
+                        .get(*param)
+                        .is_none_or(|v| t.pointer(field).is_some_and(|f| equals(f, v)))
+                })
+            })
+            .filter(|t| {
+                let project = t.pointer("/project/path_with_namespace");
+                query.get("project_id").is_none_or(|id| {
+                    let path = self.projects.iter().find(|p| p.0.to_string() == *id);
+                    path.is_some_and(|(_, path)| project == Some(&json!(path)))
+                })
+            })
+            // Todos of the group and of the projects in it, including subgroups
+            .filter(|t| {
+                query.get("group_id").is_none_or(|id| {
+                    let group = self.groups.iter().find(|g| g.0.to_string() == *id);
+                    group.is_some_and(|(_, group)| {
+                        let in_group = |path: &Value| {
+                            path.as_str()
+                                .is_some_and(|p| p == group || p.starts_with(&format!("{group}/")))
+                        };
+                        in_group(&t["group"]["full_path"])
+                            || in_group(&t["project"]["path_with_namespace"])
+                    })
+                })
+            })
+            .collect();
+        todos.sort_by_key(|t| std::cmp::Reverse(t["id"].as_u64()));
+
+        let param = |name, default| {
+            query
+                .get(name)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        let per_page = self.page_size.map_or(param("per_page", 20), |size| {
+            size.min(param("per_page", 20))
+        });
+        let page = param("page", 1).max(1);
+        let total_pages = todos.len().div_ceil(per_page).max(1);
+        let items: Vec<&Value> = todos
+            .iter()
+            .skip((page - 1) * per_page)
+            .take(per_page)
+            .copied()
+            .collect();
+        let next = if page < total_pages {
+            (page + 1).to_string()
+        } else {
+            String::new()
+        };
+        let headers = [
+            ("x-page", page.to_string()),
+            ("x-per-page", per_page.to_string()),
+            ("x-next-page", next),
+            ("x-total", todos.len().to_string()),
+            ("x-total-pages", total_pages.to_string()),
+        ];
+        (headers, axum::Json(items)).into_response()
+    }
+}
+
+/// Whether a JSON string or number has the value of a query parameter
+fn equals(value: &Value, param: &str) -> bool {
+    match value {
+        Value::Number(n) => n.to_string() == param,
+        value => value.as_str() == Some(param),
+    }
+}
+
+fn ok(value: Value) -> Response {
+    axum::Json(value).into_response()
+}
+
+fn not_found() -> Response {
+    let message = json!({"message": "404 Not Found"});
+    (StatusCode::NOT_FOUND, axum::Json(message)).into_response()
+}
+
+fn entity(entities: &[(u64, String)], id: &str) -> Response {
+    match entities
+        .iter()
+        .find(|(i, path)| i.to_string() == id || path == id)
+    {
+        Some((id, path)) => ok(json!({"id": id, "path_with_namespace": path, "full_path": path})),
+        None => not_found(),
+    }
+}
+
+/// Decodes a percent-encoded path segment, e.g. "main%2Fapp"
+fn decode(segment: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = segment.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|h| std::str::from_utf8(h).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(decoded) if b == b'%' => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// A temporary directory removed when dropped
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNT.fetch_add(1, Ordering::Relaxed);
+        let dir =
+            std::env::temp_dir().join(format!("gitlab-todotxt-e2e-{}-{n}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+
+    pub fn read(&self, name: &str) -> String {
+        std::fs::read_to_string(self.path(name)).unwrap_or_default()
+    }
+
+    pub fn write(&self, name: &str, content: &str) {
+        std::fs::write(self.path(name), content).unwrap();
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,454 @@
+//! End-to-end tests of the sync against a mock Gitlab
+
+mod common;
+
+use common::{done, todo, MockGitlab, TestDir, TOKEN};
+use gitlab_todotxt_sync::config::DonePolicy;
+use gitlab_todotxt_sync::doctor::Repair;
+use gitlab_todotxt_sync::filter::{Filters, Glob, TodoFilter};
+use gitlab_todotxt_sync::notifier::NotifyConfig;
+use gitlab_todotxt_sync::report::SyncReport;
+use gitlab_todotxt_sync::session::Session;
+use gitlab_todotxt_sync::sync::SyncEngine;
+use gitlab_todotxt_sync::todo::{Date, Todo};
+use gitlab_todotxt_sync::Error;
+use reqwest::Method;
+use serde_json::json;
+
+/// The lines of a todo file, sorted as new todos are added in no particular order
+fn lines(content: &str) -> Vec<&str> {
+    let mut lines: Vec<&str> = content.lines().collect();
+    lines.sort();
+    lines
+}
+
+#[tokio::test]
+async fn test_paginated_sync() {
+    let gitlab = MockGitlab::start().await;
+    gitlab.set_page_size(2);
+    for id in 1..=5 {
+        gitlab.add_todo(todo(id, "main/app", &format!("Review #{id}")));
+    }
+    gitlab.add_todo(done(todo(6, "main/app", "Merged")));
+    let dir = TestDir::new();
+    dir.write("todo.txt", "(A) Call mom\n");
+    let mut config = gitlab.config(&dir);
+    config.done_todo_policy = DonePolicy::Add;
+
+    let outcome = SyncEngine::new(config).unwrap().run().await.unwrap();
+    assert_eq!(outcome.files[0].changes.counts(), (6, 0, 0));
+    assert_eq!(
+        lines(&dir.read("todo.txt")),
+        [
+            "(A) Call mom",
+            "2024-01-01 [MergeRequest:review_requested] Review #1 +main/app id:1 @gitlab",
+            "2024-01-02 [MergeRequest:review_requested] Review #2 +main/app id:2 @gitlab",
+            "2024-01-03 [MergeRequest:review_requested] Review #3 +main/app id:3 @gitlab",
+            "2024-01-04 [MergeRequest:review_requested] Review #4 +main/app id:4 @gitlab",
+            "2024-01-05 [MergeRequest:review_requested] Review #5 +main/app id:5 @gitlab",
+            "x 2024-02-01 2024-01-06 [MergeRequest:review_requested] Merged +main/app id:6 @gitlab",
+        ]
+    );
+
+    let calls = gitlab.calls();
+    assert!(calls.iter().all(|c| c.token.as_deref() == Some(TOKEN)));
+    let pages = |state: &str| {
+        let mut pages: Vec<String> = gitlab
+            .calls_to(Method::GET, "todos")
+            .into_iter()
+            .filter(|c| c.query["state"] == state)
+            .map(|c| c.query["page"].clone())
+            .collect();
+        pages.sort();
+        pages
+    };
+    assert_eq!(pages("pending"), ["1", "2", "3"]);
+    assert_eq!(pages("done"), ["1"]);
+}
+
+#[tokio::test]
+async fn test_incremental_sync_with_push() {
+    let gitlab = MockGitlab::start().await;
+    gitlab.add_todo(done(todo(1, "main/app", "Old")));
+    gitlab.add_todo(todo(2, "main/app", "Review"));
+    let dir = TestDir::new();
+    let mut config = gitlab.config(&dir);
+    config.push_completed = true;
+    let engine = SyncEngine::new(config).unwrap();
+    engine.run().await.unwrap();
+
+    let content = dir
+        .read("todo.txt")
+        .replace("2024-01-02 [", "x 2024-03-01 2024-01-02 [");
+    dir.write("todo.txt", &content);
+    gitlab.add_todo(todo(3, "other/app", "New"));
+    let outcome = engine.run().await.unwrap();
+    assert_eq!(outcome.pushed, 1);
+    assert_eq!(outcome.new_todos.len(), 1);
+    let totals = SyncReport::from(&outcome).totals;
+    assert_eq!((totals.added, totals.pushed, totals.conflicts), (1, 1, 0));
+    assert_eq!(gitlab.todo_state(2).as_deref(), Some("done"));
+    assert_eq!(
+        gitlab.calls_to(Method::POST, "todos/2/mark_as_done").len(),
+        1
+    );
+    assert_eq!(
+        dir.read("todo.txt"),
+        "x 2024-02-01 2024-01-02 [MergeRequest:review_requested] Review +main/app id:2 @gitlab\n\
+        2024-01-03 [MergeRequest:review_requested] New +other/app id:3 @gitlab\n"
+    );
+}
+
+#[tokio::test]
+async fn test_retries() {
+    let gitlab = MockGitlab::start().await;
+    gitlab.add_todo(todo(1, "main/app", "Review"));
+    gitlab.fail_next("todos", 503);
+    gitlab.fail_next("todos", 429);
+    let dir = TestDir::new();
+
+    SyncEngine::new(gitlab.config(&dir))
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+    assert!(dir.read("todo.txt").contains("id:1"));
+    assert!(gitlab.calls_to(Method::GET, "todos").len() >= 4);
+}
+
+#[tokio::test]
+async fn test_api_errors() {
+    let gitlab = MockGitlab::start().await;
+    gitlab.add_todo(todo(1, "main/app", "Review"));
+    let dir = TestDir::new();
+    dir.write("todo.txt", "(A) Call mom\n");
+
+    let mut config = gitlab.config(&dir);
+    config.gitlab_token.0 = "glpat-revoked".into();
+    let error = SyncEngine::new(config).unwrap().run().await.unwrap_err();
+    assert_eq!(error.exit_code(), 77, "{error}");
+
+    // Both the pending and done todos are retried twice
+    for _ in 0..6 {
+        gitlab.fail_next("todos", 500);
+    }
+    let error = SyncEngine::new(gitlab.config(&dir))
+        .unwrap()
+        .run()
+        .await
+        .unwrap_err();
+    assert!(matches!(error, Error::Api(_)), "{error}");
+    assert_eq!(error.exit_code(), 69);
+    assert_eq!(dir.read("todo.txt"), "(A) Call mom\n");
+}
+
+#[tokio::test]
+async fn test_server_side_filter() {
+    let gitlab = MockGitlab::start().await;
+    gitlab.add_project(12, "main/app");
+    gitlab.add_todo(todo(1, "main/app", "Review"));
+    gitlab.add_todo(todo(2, "other/app", "Other"));
+    let dir = TestDir::new();
+    let filter = |project: &str| Filters {
+        include: vec![TodoFilter {
+            project: Some(Glob::new(project).unwrap()),
+            ..Default::default()
+        }],
+        exclude: Vec::new(),
+    };
+
+    let mut config = gitlab.config(&dir);
+    config.filters = filter("main/app");
+    SyncEngine::new(config).unwrap().run().await.unwrap();
+    let content = dir.read("todo.txt");
+    assert!(
+        content.contains("id:1") && !content.contains("id:2"),
+        "{content}"
+    );
+    let todos = gitlab.calls_to(Method::GET, "todos");
+    assert!(todos
+        .iter()
+        .all(|c| c.query.get("project_id").map(String::as_str) == Some("12")));
+
+    let mut config = gitlab.config(&dir);
+    config.filters = filter("missing/app");
+    let error = SyncEngine::new(config).unwrap().run().await.unwrap_err();
+    assert!(matches!(error, Error::Config(_)), "{error}");
+
+    // Groups and authors are filtered by id too
+    gitlab.add_group(5, "main");
+    gitlab.add_todo(todo(3, "main/lib", "Library"));
+    let mut by_bob = todo(4, "other/app", "Bob's");
+    by_bob["author"] = json!({"id": 3, "username": "bob"});
+    gitlab.add_todo(by_bob);
+    let filtered = |filter: TodoFilter| {
+        let mut config = gitlab.config(&dir);
+        config.filters.include = vec![filter];
+        async { SyncEngine::new(config).unwrap().run().await.unwrap() }
+    };
+    let last_query = |param: &str| {
+        let todos = gitlab.calls_to(Method::GET, "todos");
+        todos.last().unwrap().query.get(param).cloned()
+    };
+    filtered(TodoFilter {
+        group: Some(Glob::new("main").unwrap()),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(last_query("group_id").as_deref(), Some("5"));
+    let content = dir.read("todo.txt");
+    assert!(
+        content.contains("id:1") && content.contains("id:3") && !content.contains("id:4"),
+        "{content}"
+    );
+    filtered(TodoFilter {
+        author: Some(Glob::new("bob").unwrap()),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(last_query("author_id").as_deref(), Some("3"));
+    assert_eq!(lines(&dir.read("todo.txt")).len(), 1);
+    assert!(dir.read("todo.txt").contains("id:4"));
+}
+
+#[tokio::test]
+async fn test_record_replay() {
+    let gitlab = MockGitlab::start().await;
+    gitlab.set_page_size(1);
+    gitlab.add_todo(todo(1, "main/app", "Review"));
+    gitlab.add_todo(todo(2, "main/app", "Secret"));
+    let dir = TestDir::new();
+    let session = dir.path("session");
+
+    let recorder = Session::record(&session, true).unwrap();
+    let engine = SyncEngine::new(gitlab.config(&dir)).unwrap();
+    engine.with_session(recorder).run().await.unwrap();
+    let recorded = dir.read("todo.txt");
+    let calls = gitlab.calls().len();
+
+    // Replaying on a fresh file, against a host that would refuse connections, and ignoring the
+    // state of another account
+    let replay_dir = TestDir::new();
+    let other_state = r#"{"user": {"id": 99, "username": "other"}}"#;
+    replay_dir.write("state.json", other_state);
+    let mut config = gitlab.config(&replay_dir);
+    config.gitlab_host = "http://127.0.0.1:9/".parse().unwrap();
+    let replayer = Session::replay(&session).unwrap();
+    let engine = SyncEngine::new(config).unwrap().with_session(replayer);
+    engine.run().await.unwrap();
+    let redacted = recorded
+        .replace("] Review ", "] [redacted] ")
+        .replace("] Secret ", "] [redacted] ");
+    assert_eq!(lines(&replay_dir.read("todo.txt")), lines(&redacted));
+    assert_eq!(gitlab.calls().len(), calls);
+    assert_eq!(replay_dir.read("state.json"), other_state);
+    assert!(!std::fs::read_dir(&session)
+        .unwrap()
+        .any(|f| std::fs::read_to_string(f.unwrap().path())
+            .unwrap()
+            .contains(TOKEN)));
+}
+
+#[tokio::test]
+async fn test_doctor() {
+    let gitlab = MockGitlab::start().await;
+    gitlab.add_todo(todo(1, "main/app", "Review"));
+    let dir = TestDir::new();
+    let file = "(A) Call mom\n\
+        2024-01-01 [MergeRequest:review_requested] Review +main/app id:1 @gitlab\n\
+        2024-01-01 [MergeRequest:review_requested] Review +main/app id:1 due:2024-03-01 @gitlab\n\
+        Pasted @gitlab\n\
+        2023-01-01 Old job id:99 @gitlab\n";
+    dir.write("todo.txt", file);
+    let engine = SyncEngine::new(gitlab.config(&dir)).unwrap();
+
+    let diagnoses = engine.doctor(None).await.unwrap();
+    let problems: Vec<String> = diagnoses[0]
+        .problems
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+    assert_eq!(problems.len(), 3, "{problems:?}");
+    assert!(
+        problems[2].starts_with("2 lines have the id 1"),
+        "{problems:?}"
+    );
+    assert_eq!(dir.read("todo.txt"), file);
+
+    let diagnoses = engine.doctor(Some(Repair::Quarantine)).await.unwrap();
+    assert!(diagnoses[0].repaired);
+    assert_eq!(
+        dir.read("todo.txt"),
+        "(A) Call mom\n\
+        2024-01-01 [MergeRequest:review_requested] Review +main/app id:1 @gitlab due:2024-03-01\n\
+        Pasted @gitlab-quarantine\n\
+        2023-01-01 Old job id:99 @gitlab-quarantine\n"
+    );
+    assert!(engine.doctor(None).await.unwrap()[0].problems.is_empty());
+
+    // The sync repairs what it can tell without fetching every todo
+    dir.write("todo.txt", file);
+    let mut config = gitlab.config(&dir);
+    config.repair = Some(Repair::Strip);
+    let outcome = SyncEngine::new(config).unwrap().run().await.unwrap();
+    assert_eq!(outcome.files[0].changes.counts(), (0, 0, 1));
+    assert_eq!(
+        dir.read("todo.txt"),
+        "(A) Call mom\n\
+        Pasted\n\
+        2024-01-01 [MergeRequest:review_requested] Review +main/app id:1 @gitlab due:2024-03-01\n"
+    );
+}
+
+#[tokio::test]
+async fn test_edit_todos() {
+    let gitlab = MockGitlab::start().await;
+    gitlab.add_todo(todo(1, "main/app", "Review"));
+    gitlab.add_todo(todo(2, "main/app", "Fix"));
+    let dir = TestDir::new();
+    let engine = SyncEngine::new(gitlab.config(&dir))
+        .unwrap()
+        .with_push_completed(true);
+    let outcome = engine.run().await.unwrap();
+    let content = String::from_utf8(outcome.files[0].content.clone()).unwrap();
+    let edited: Vec<Todo> = content
+        .lines()
+        .map(|l| l.parse().unwrap())
+        .map(|t: Todo| match t.get_data("id") {
+            Some("1") => Todo {
+                done: true,
+                completed: Some("2024-03-01".parse().unwrap()),
+                ..t
+            },
+            _ => Todo {
+                priority: Some('A'),
+                ..t
+            },
+        })
+        .collect();
+
+    let outcome = engine.edit_todos(&edited).await.unwrap();
+    assert_eq!(outcome.pushed, 1);
+    assert_eq!(gitlab.todo_state(1).as_deref(), Some("done"));
+    assert_eq!(
+        lines(&dir.read("todo.txt")),
+        [
+            "(A) 2024-01-02 [MergeRequest:review_requested] Fix +main/app id:2 @gitlab",
+            "x 2024-02-01 2024-01-01 [MergeRequest:review_requested] Review +main/app id:1 @gitlab",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_snooze() {
+    let gitlab = MockGitlab::start().await;
+    gitlab.add_todo(todo(1, "main/app", "Review"));
+    gitlab.add_todo(todo(2, "main/app", "Fix"));
+    let dir = TestDir::new();
+    let mut config = gitlab.config(&dir);
+    config.snoozed_file = Some(dir.path("snoozed.txt"));
+    config.hide_future_threshold = true;
+    let engine = SyncEngine::new(config).unwrap();
+    engine.run().await.unwrap();
+
+    let until = Date::today().add_days(7);
+    engine.snooze(1, until).await.unwrap();
+    assert_eq!(
+        dir.read("todo.txt"),
+        "2024-01-02 [MergeRequest:review_requested] Fix +main/app id:2 @gitlab\n"
+    );
+    let snoozed = format!(
+        "2024-01-01 [MergeRequest:review_requested] Review +main/app id:1 @gitlab t:{until} h:1\n"
+    );
+    assert_eq!(dir.read("snoozed.txt"), snoozed);
+    engine.run().await.unwrap();
+    assert_eq!(dir.read("snoozed.txt"), snoozed);
+
+    // Once the threshold passes, the todo goes back with its tags
+    let past = Date::today().add_days(-1);
+    dir.write(
+        "snoozed.txt",
+        &snoozed.replace(&until.to_string(), &past.to_string()),
+    );
+    engine.run().await.unwrap();
+    assert_eq!(dir.read("snoozed.txt"), "");
+    assert!(dir
+        .read("todo.txt")
+        .contains(&format!("Review +main/app id:1 @gitlab t:{past}\n")));
+
+    let error = engine.snooze(3, until).await.unwrap_err();
+    assert!(matches!(error, Error::Parse(_)), "{error}");
+
+    // Snoozing most todos moves them, which doesn't count as deleting them
+    for id in 3..=12 {
+        gitlab.add_todo(todo(id, "main/app", "Other"));
+    }
+    engine.run().await.unwrap();
+    let snoozed: String = dir
+        .read("todo.txt")
+        .lines()
+        .map(|l| format!("{} t:{until}\n", l.replace(&format!(" t:{past}"), "")))
+        .collect();
+    dir.write("todo.txt", &snoozed);
+    engine.run().await.unwrap();
+    assert_eq!(dir.read("todo.txt"), "");
+    assert_eq!(dir.read("snoozed.txt").lines().count(), 12);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_hooks() {
+    let gitlab = MockGitlab::start().await;
+    for id in 1..=10 {
+        gitlab.add_todo(todo(id, "main/app", "Review"));
+    }
+    let dir = TestDir::new();
+    let sh = |script: String| Some(vec!["sh".to_string(), "-c".into(), script]);
+    let mut config = gitlab.config(&dir);
+    config.notifications = Some(NotifyConfig {
+        actions: Vec::new(),
+        title: String::new(),
+        body: String::new(),
+        command: sh(format!("echo {{id}} >> {}", dir.path("notified").display())),
+    });
+    SyncEngine::new(config.clone())
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+    let synced = dir.read("todo.txt");
+
+    // The lines printed by after_merge go through the deletion check too
+    let plan = format!(
+        r#"{{"files": [{{"path": "{}", "lines": []}}]}}"#,
+        config.todo_file.display()
+    );
+    config.hooks.after_merge = sh(format!("echo '{plan}'"));
+    let engine = SyncEngine::new(config.clone()).unwrap();
+    let error = engine.run().await.unwrap_err();
+    assert!(matches!(error, Error::Aborted(_)), "{error}");
+    assert_eq!(dir.read("todo.txt"), synced);
+
+    // The outcome reports the changes printed by after_merge, here dropping a new todo
+    gitlab.add_todo(todo(11, "main/app", "Fix"));
+    let lines: Vec<&str> = synced.lines().collect();
+    let plan = json!({"files": [{"path": config.todo_file, "lines": lines}]});
+    config.hooks.after_merge = sh(format!("echo '{plan}'"));
+    let engine = SyncEngine::new(config.clone()).unwrap();
+    let outcome = engine.run().await.unwrap();
+    assert_eq!(dir.read("todo.txt"), synced);
+    assert!(outcome.files[0].changes.0.is_empty());
+    assert_eq!(SyncReport::from(&outcome).totals.added, 0);
+    assert!(outcome.new_todos.is_empty());
+    assert!(!dir.path("notified").exists());
+
+    // A failing after_write hook doesn't fail the sync, nor prevent the notifications
+    gitlab.add_todo(todo(12, "main/app", "Fix"));
+    config.hooks.after_merge = None;
+    config.hooks.after_write = sh("exit 1".into());
+    let engine = SyncEngine::new(config).unwrap();
+    let outcome = engine.run().await.unwrap();
+    assert!(dir.read("todo.txt").contains("Fix +main/app id:11"));
+    assert_eq!(outcome.files[0].changes.counts(), (2, 0, 0));
+    assert_eq!(dir.read("notified"), "12\n");
+}